@@ -0,0 +1,104 @@
+//! Measures the time and number of allocations taken to expand grammars.
+//!
+//! Run with `cargo bench`.
+
+use rand::{rngs::StdRng, SeedableRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tracery::{Grammar, Result};
+
+/// Counts every allocation made through the system allocator
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A chain of keys, each of whose rules mixes text, tags, modifiers and
+/// actions before expanding the next key in the chain. The chain may be deeper
+/// than the default maximum depth, so the grammar has no maximum depth
+fn deep(depth: usize) -> Result<Grammar> {
+    let mut map: Vec<(String, Vec<String>)> = (0..depth)
+        .map(|i| {
+            let rules = (0..4)
+                .map(|j| {
+                    format!(
+                        "level {} rule {}: the #adjective# #noun.capitalize# [hero:#noun#]{}, \
+                         with #hero.a# and #noun.s#, #adjective.replace(a,e)#",
+                        i,
+                        j,
+                        if i + 1 < depth {
+                            format!("#level{}#", i + 1)
+                        } else {
+                            "the end".to_string()
+                        }
+                    )
+                })
+                .collect();
+            (format!("level{}", i), rules)
+        })
+        .collect();
+    map.push(("origin".into(), vec!["#level0#".into()]));
+    map.push(("adjective".into(), vec!["quick".into(), "lazy".into()]));
+    map.push((
+        "noun".into(),
+        vec!["fox".into(), "dog".into(), "owl".into()],
+    ));
+    Ok(Grammar::from_map(map)?.with_max_depth(None))
+}
+
+/// A flat grammar with many keys, of which each output only uses a few
+fn wide(keys: usize) -> Result<Grammar> {
+    let mut map: Vec<(String, Vec<String>)> = (0..keys)
+        .map(|i| (format!("key{}", i), vec![format!("value {}", i); 8]))
+        .collect();
+    map.push(("origin".into(), vec!["#key0# #key1# [a:b]#a#".into()]));
+    Grammar::from_map(map)
+}
+
+fn bench(name: &str, grammar: &Grammar, iterations: usize) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    // Warm up, so that one-time allocations are not counted
+    grammar.flatten(&mut rng)?;
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..iterations {
+        grammar.flatten(&mut rng)?;
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{:<12} {:>10.1} us/flatten {:>10} allocations/flatten",
+        name,
+        elapsed.as_secs_f64() * 1e6 / iterations as f64,
+        allocations / iterations
+    );
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    bench("deep(16)", &deep(16)?, 2000)?;
+    bench("deep(128)", &deep(128)?, 200)?;
+    bench("wide(10000)", &wide(10_000)?, 2000)?;
+    Ok(())
+}
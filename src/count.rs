@@ -0,0 +1,593 @@
+use num_bigint::BigUint;
+use rand::Rng;
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    distribution::candidates,
+    tag::{Action, Tag},
+    Error, Grammar, MissingKey, MissingKeyPolicy, Node, Overlay, Result, Rule,
+};
+
+/// The number of derivations a [`Grammar`] can produce for a key. Returned by
+/// [`Grammar::count_outputs`].
+///
+/// [`Grammar`]: struct.Grammar.html
+/// [`Grammar::count_outputs`]: struct.Grammar.html#method.count_outputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputCount {
+    /// The grammar can produce exactly this many derivations
+    Finite(BigUint),
+    /// The grammar is recursive, and can produce infinitely many derivations
+    Unbounded,
+}
+
+/// A ruleset on an abstract rule stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Ruleset {
+    /// The ruleset at the given index of the grammar's rule stack
+    Grammar(usize),
+    /// A ruleset of the given number of plain text rules pushed by an action,
+    /// each of which has exactly one derivation
+    Pushed(usize),
+}
+
+/// The abstract rule stacks of every key that has been pushed or popped.
+/// Other keys use the grammar's rule stacks
+type Env = BTreeMap<String, Vec<Ruleset>>;
+
+/// The number of derivations leading to each distinct set of rule stacks
+type Counts = Vec<(BigUint, Env)>;
+
+/// Reasons for counting to stop early
+enum Halt {
+    Unbounded,
+    Error(Error),
+}
+
+impl From<Error> for Halt {
+    fn from(e: Error) -> Halt {
+        Halt::Error(e)
+    }
+}
+
+/// Adds the given count to a list of counts, merging it with any count that
+/// has the same rule stacks
+fn merge(counts: &mut Counts, count: BigUint, env: Env) {
+    match counts.iter_mut().find(|(_, e)| *e == env) {
+        Some((c, _)) => *c += count,
+        None => counts.push((count, env)),
+    }
+}
+
+/// Counts of the derivations of keys, kept over the course of an execution
+/// for as long as the rule stacks are unchanged
+#[derive(Debug, Default)]
+pub(crate) struct Derivations {
+    /// Previously counted expansions of keys, each with the number of levels
+    /// of nesting below the key that counting it reached
+    memo: HashMap<(String, Env), (Counts, usize)>,
+}
+
+impl Derivations {
+    /// Forgets every count, as the rule stacks have changed
+    pub(crate) fn clear(&mut self) {
+        self.memo.clear();
+    }
+}
+
+/// Counts derivations by walking the parsed rules, tracking the effect of
+/// actions on the rule stacks
+struct Counter<'a> {
+    grammar: &'a Grammar,
+    /// The changes made to the grammar's rule stacks before counting began
+    overlay: &'a Overlay,
+    /// The keys being expanded before counting began, each with the index of
+    /// the rule chosen for it
+    prefix: Vec<(String, usize)>,
+    /// The keys being expanded, along with the ruleset each was expanded from
+    /// and the index of the rule being counted
+    path: Vec<(String, Ruleset, usize)>,
+    /// The deepest nesting of keys reached so far, counting the prefix
+    reached: usize,
+    /// Previously counted expansions of keys
+    derivations: &'a mut Derivations,
+}
+
+impl<'a> Counter<'a> {
+    /// Creates the error for a reference to a missing key
+    fn missing_key(&self, key: &str, env: &Env) -> Error {
+        let mut path = self.prefix.clone();
+        path.extend(self.path.iter().map(|(k, _, i)| (k.clone(), *i)));
+        let popped = env.contains_key(key) || self.overlay.was_popped(self.grammar, key);
+        Error::MissingKeyError(MissingKey::new(key, path, popped))
+    }
+
+    /// Gets the abstract rule stack for a key
+    fn stack(&self, key: &str, env: &Env) -> Vec<Ruleset> {
+        match env.get(key) {
+            Some(stack) => stack.clone(),
+            None => {
+                let depth = self.overlay.depth(self.grammar, key);
+                (0..depth).map(Ruleset::Grammar).collect()
+            }
+        }
+    }
+
+    fn count_key(&mut self, key: &str, env: Env) -> std::result::Result<Counts, Halt> {
+        let ruleset = match self.stack(key, &env).last() {
+            Some(&ruleset) => ruleset,
+            None => {
+                return match self.grammar.missing_key_policy() {
+                    MissingKeyPolicy::Error => Err(self.missing_key(key, &env).into()),
+                    _ => Ok(vec![(BigUint::from(1u32), env)]),
+                }
+            }
+        };
+        let index = match ruleset {
+            Ruleset::Pushed(len) => return Ok(vec![(BigUint::from(len), env)]),
+            Ruleset::Grammar(index) => index,
+        };
+        if self.path.iter().any(|(k, r, _)| k == key && *r == ruleset) {
+            return Err(Halt::Unbounded);
+        }
+        let depth = self.prefix.len() + self.path.len() + 1;
+        let max_depth = self.grammar.max_depth().unwrap_or(usize::MAX);
+        if depth > max_depth {
+            let mut path: Vec<String> = self.prefix.iter().map(|(k, _)| k.clone()).collect();
+            path.extend(self.path.iter().map(|(k, _, _)| k.clone()));
+            path.push(key.to_string());
+            return Err(Error::RecursionLimit(path).into());
+        }
+        let memo_key = (key.to_string(), env);
+        match self.derivations.memo.get(&memo_key) {
+            // A count which reached too deep is counted again, to fail with
+            // the path of keys which exceeds the maximum depth
+            Some((counts, height)) if depth + height <= max_depth => {
+                self.reached = self.reached.max(depth + height);
+                return Ok(counts.clone());
+            }
+            _ => {}
+        }
+        let env = memo_key.1.clone();
+        let rules = self.overlay.ruleset(self.grammar, key, index);
+        if rules.is_empty() {
+            return Err(Error::EmptyRuleset(key.to_string()).into());
+        }
+
+        let outer = std::mem::replace(&mut self.reached, depth);
+        self.path.push((key.to_string(), ruleset, 0));
+        let mut counts = Counts::new();
+        for choice in candidates(rules) {
+            self.path.last_mut().unwrap().2 = choice;
+            for (count, env) in self.count_nodes(&rules[choice].nodes, env.clone())? {
+                merge(&mut counts, count, env);
+            }
+        }
+        self.path.pop();
+        let height = self.reached - depth;
+        self.reached = self.reached.max(outer);
+
+        self.derivations
+            .memo
+            .insert(memo_key, (counts.clone(), height));
+        Ok(counts)
+    }
+
+    fn count_nodes(&mut self, nodes: &[Node], env: Env) -> std::result::Result<Counts, Halt> {
+        let mut counts = vec![(BigUint::from(1u32), env)];
+        for node in nodes {
+            if let Node::Tag(tag) = node {
+                let mut next = Counts::new();
+                for (count, env) in counts {
+                    for (c, env) in self.count_tag(tag, env)? {
+                        merge(&mut next, &count * c, env);
+                    }
+                }
+                counts = next;
+            }
+        }
+        Ok(counts)
+    }
+
+    fn count_tag(&mut self, tag: &Tag, env: Env) -> std::result::Result<Counts, Halt> {
+        let mut counts = vec![(BigUint::from(1u32), env)];
+        for action in &tag.actions {
+            counts = self.count_action(action, counts)?;
+        }
+
+        if let Some(key) = &tag.key {
+            let mut next = Counts::new();
+            for (count, env) in counts {
+                for (c, env) in self.count_key(key, env)? {
+                    merge(&mut next, &count * c, env);
+                }
+            }
+            counts = next;
+        }
+        Ok(counts)
+    }
+
+    fn count_action(
+        &mut self,
+        action: &Action,
+        counts: Counts,
+    ) -> std::result::Result<Counts, Halt> {
+        let mut next = Counts::new();
+        for (count, mut env) in counts {
+            match &action.label {
+                Some(label) if action.is_pop() => {
+                    let mut stack = self.stack(label, &env);
+                    stack.pop();
+                    env.insert(label.clone(), stack);
+                    merge(&mut next, count, env);
+                }
+                label => {
+                    let mut values = vec![(count, env)];
+                    for rule in &action.rules {
+                        let mut after = Counts::new();
+                        for (count, env) in values {
+                            for (c, env) in self.count_nodes(&rule.nodes, env)? {
+                                merge(&mut after, &count * c, env);
+                            }
+                        }
+                        values = after;
+                    }
+                    for (count, mut env) in values {
+                        if let Some(label) = label {
+                            let mut stack = self.stack(label, &env);
+                            stack.push(Ruleset::Pushed(action.rules.len()));
+                            env.insert(label.clone(), stack);
+                        }
+                        merge(&mut next, count, env);
+                    }
+                }
+            }
+        }
+        Ok(next)
+    }
+}
+
+impl<'a> Counter<'a> {
+    fn new(
+        grammar: &'a Grammar,
+        overlay: &'a Overlay,
+        derivations: &'a mut Derivations,
+        prefix: Vec<(String, usize)>,
+    ) -> Counter<'a> {
+        Counter {
+            grammar,
+            overlay,
+            prefix,
+            path: Vec::new(),
+            reached: 0,
+            derivations,
+        }
+    }
+}
+
+/// Counts the derivations of a key in the given grammar
+pub(crate) fn count_outputs(grammar: &Grammar, key: &str) -> Result<OutputCount> {
+    let overlay = Overlay::default();
+    let mut derivations = Derivations::default();
+    match Counter::new(grammar, &overlay, &mut derivations, Vec::new()).count_key(key, Env::new()) {
+        Ok(counts) => Ok(OutputCount::Finite(
+            counts.into_iter().map(|(count, _)| count).sum(),
+        )),
+        Err(Halt::Unbounded) => Ok(OutputCount::Unbounded),
+        Err(Halt::Error(e)) => Err(e),
+    }
+}
+
+/// Generates a random number in the range `[0, n)`, where `n` is not zero
+fn random_below<R>(n: &BigUint, rng: &mut R) -> BigUint
+where
+    R: ?Sized + Rng,
+{
+    let bits = n.bits() as usize;
+    // `usize::div_ceil` needs Rust 1.73
+    let bytes_len = (bits + 7) / 8;
+    let unused_bits = bytes_len * 8 - bits;
+    let mut bytes = vec![0u8; bytes_len];
+    loop {
+        rng.fill_bytes(&mut bytes);
+        // Clear the bits above the highest bit of n, so that at least half of
+        // the candidates are in range
+        *bytes.last_mut().unwrap() &= 0xff >> unused_bits;
+        let candidate = BigUint::from_bytes_le(&bytes);
+        if candidate < *n {
+            return candidate;
+        }
+    }
+}
+
+/// Chooses a rule from the ruleset of the given key with probability
+/// proportional to its weight multiplied by its number of derivations, so that
+/// with equal weights every derivation of the key is equally likely. Counts
+/// are kept in `derivations`, to be reused by later choices
+pub(crate) fn choose_by_derivations<R>(
+    grammar: &Grammar,
+    overlay: &Overlay,
+    derivations: &mut Derivations,
+    key: &str,
+    rules: &[Rule],
+    path: Vec<(String, usize)>,
+    rng: &mut R,
+) -> Result<usize>
+where
+    R: ?Sized + Rng,
+{
+    let mut counter = Counter::new(grammar, overlay, derivations, path);
+    let choices = candidates(rules);
+    let mut weights = Vec::with_capacity(choices.len());
+    for &choice in &choices {
+        counter.prefix.push((key.to_string(), choice));
+        let counts = match counter.count_nodes(&rules[choice].nodes, Env::new()) {
+            Ok(counts) => counts,
+            Err(Halt::Unbounded) => return Err(Error::UnboundedDerivations(key.to_string())),
+            Err(Halt::Error(e)) => return Err(e),
+        };
+        counter.prefix.pop();
+        let count: BigUint = counts.into_iter().map(|(count, _)| count).sum();
+        weights.push(count * rules[choice].weight.max(1));
+    }
+
+    let total: BigUint = weights.iter().sum();
+    if total == BigUint::default() {
+        return Ok(choices[0]);
+    }
+    let mut target = random_below(&total, rng);
+    for (choice, weight) in choices.into_iter().zip(weights) {
+        if target < weight {
+            return Ok(choice);
+        }
+        target -= weight;
+    }
+    unreachable!("target is less than the total weight")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OutputCount;
+    use crate::{grammar, Error, Result};
+
+    fn finite(n: u32) -> OutputCount {
+        OutputCount::Finite(n.into())
+    }
+
+    #[test]
+    fn count_simple() -> Result<()> {
+        let g = grammar! {
+            "origin" => ["#a# #b#", "#a#"],
+            "a" => ["x", "y"],
+            "b" => ["1", "2", "1"],
+            "zero" => [("a", 0), ("b", 1)]
+        }?;
+        assert_eq!(g.count_outputs("origin")?, finite(8));
+        assert_eq!(g.count_outputs("b")?, finite(3));
+        assert_eq!(g.count_outputs("zero")?, finite(1));
+        Ok(())
+    }
+
+    #[test]
+    fn count_matches_enumerate() -> Result<()> {
+        let g = grammar! {
+            "origin" => ["#[hero:#name#]story#", "#story#"],
+            "story" => "#hero# met #name#",
+            "hero" => ["a", "b", "c"],
+            "name" => ["d", "e"]
+        }?;
+        let outputs = g.enumerate("origin").collect::<Result<Vec<_>>>()?;
+        assert_eq!(outputs.len(), 10);
+        assert_eq!(g.count_outputs("origin")?, finite(10));
+        Ok(())
+    }
+
+    #[test]
+    fn count_pop() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#[x:a]y#",
+            "y" => "#x##[x:POP]x#",
+            "x" => ["b", "c"]
+        }?;
+        assert_eq!(g.count_outputs("origin")?, finite(2));
+
+        let g = grammar! {
+            "origin" => "#[x:POP]x#",
+            "x" => "b"
+        }?;
+        match g.count_outputs("origin") {
+            Err(Error::MissingKeyError(missing)) => {
+                assert_eq!(missing.to_string(), "origin[0] -> x (removed by POP)")
+            }
+            other => panic!("expected MissingKeyError, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn count_large() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#a##a##a##a##a##a##a##a##a##a##a##a##a##a##a##a#",
+            "a" => "#b##b##b##b#",
+            "b" => ["1", "2", "3", "4", "5", "6", "7", "8"]
+        }?;
+        let expected = num_bigint::BigUint::from(2u32).pow(3 * 4 * 16);
+        assert_eq!(g.count_outputs("origin")?, OutputCount::Finite(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn count_unbounded() -> Result<()> {
+        let g = grammar! {
+            "origin" => ["a", "#b#"],
+            "b" => ["#c#"],
+            "c" => ["x", "#b#"],
+            "pushed" => "#[b:x]c#"
+        }?;
+        assert_eq!(g.count_outputs("origin")?, OutputCount::Unbounded);
+        assert_eq!(g.count_outputs("pushed")?, finite(2));
+        Ok(())
+    }
+
+    #[test]
+    fn count_empty_ruleset() -> Result<()> {
+        let empty: Vec<&str> = Vec::new();
+        let g = crate::Grammar::from_map(vec![
+            ("origin", vec!["x", "#a#"]),
+            ("a", empty),
+            ("pushed", vec!["#[a:y]a#"]),
+        ])?;
+        let res = g.count_outputs("origin");
+        assert!(matches!(res, Err(Error::EmptyRuleset(key)) if key == "a"));
+        assert!(matches!(g.count_outputs("a"), Err(Error::EmptyRuleset(_))));
+        assert_eq!(g.count_outputs("pushed")?, finite(1));
+        Ok(())
+    }
+
+    /// A chain of keys, each of whose rules is made by the given function from
+    /// the name of the next key in the chain
+    fn chain(len: usize, rule: fn(&str) -> String) -> Result<crate::Grammar> {
+        let mut map: Vec<(String, Vec<String>)> = (0..len)
+            .map(|i| (format!("k{}", i), vec![rule(&format!("k{}", i + 1))]))
+            .collect();
+        map.push((format!("k{}", len), vec!["end".into()]));
+        crate::Grammar::from_map(map)
+    }
+
+    #[test]
+    fn count_deep_chain() -> Result<()> {
+        for rule in [
+            |k: &str| format!("#{}#", k),
+            |k: &str| format!("#[x:#{}#]x#", k),
+        ] {
+            let g = chain(10_000, rule)?;
+            match g.count_outputs("k0") {
+                Err(Error::RecursionLimit(path)) => assert_eq!(path.len(), 129),
+                other => panic!("expected RecursionLimit, got {:?}", other),
+            }
+            let g = chain(100, rule)?;
+            assert_eq!(g.count_outputs("k0")?, finite(1));
+        }
+
+        // A key counted near the top is too deep to expand further down
+        let g = grammar! {
+            "origin" => ["#c#", "#a#"],
+            "a" => "#b#",
+            "b" => "#c#",
+            "c" => "#d#",
+            "d" => "x"
+        }?
+        .with_max_depth(Some(4));
+        match g.count_outputs("origin") {
+            Err(Error::RecursionLimit(path)) => assert_eq!(path, ["origin", "a", "b", "c", "d"]),
+            other => panic!("expected RecursionLimit, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uniform_derivations_deep_chain() -> Result<()> {
+        use rand::{rngs::StdRng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(11);
+        for rule in [
+            |k: &str| format!("#{}#", k),
+            |k: &str| format!("#[x:#{}#]x#", k),
+        ] {
+            let g = chain(10_000, rule)?
+                .with_uniform_derivations(true)
+                .with_default_rule("k0");
+            match g.flatten(&mut rng) {
+                Err(Error::RecursionLimit(path)) => assert_eq!(path.len(), 129),
+                other => panic!("expected RecursionLimit, got {:?}", other),
+            }
+            let g = chain(120, rule)?
+                .with_uniform_derivations(true)
+                .with_default_rule("k0");
+            assert_eq!(g.flatten(&mut rng)?, "end");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uniform_derivations() -> Result<()> {
+        use crate::Grammar;
+        use rand::{rngs::StdRng, SeedableRng};
+        use std::collections::BTreeMap;
+
+        let mut map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        map.insert("a".into(), vec!["x".into(), "#b#".into()]);
+        map.insert("b".into(), (0..100).map(|i| i.to_string()).collect());
+        let mut g = Grammar::from_map(map)?.with_uniform_derivations(true);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut xs = 0;
+        for _ in 0..2020 {
+            if g.execute("a", &mut rng)? == "x" {
+                xs += 1;
+            }
+        }
+        // x is one derivation in 101
+        assert!((5..=40).contains(&xs), "{} outputs were x", xs);
+
+        g.set_uniform_derivations(false);
+        let xs = (0..2020)
+            .map(|_| g.execute("a", &mut rng))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|s| s == "x")
+            .count();
+        assert!(xs > 800, "{} outputs were x", xs);
+        Ok(())
+    }
+
+    #[test]
+    fn uniform_derivations_after_push() -> Result<()> {
+        use crate::Grammar;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let map: Vec<(String, Vec<String>)> = vec![
+            ("origin".into(), vec!["#a# #[b:x]a#".into()]),
+            ("a".into(), vec!["y".into(), "#b#".into()]),
+            ("b".into(), (0..100).map(|i| i.to_string()).collect()),
+        ];
+        let g = Grammar::from_map(map)?.with_uniform_derivations(true);
+
+        // Once b is pushed, it has a single derivation, so the second a is
+        // as likely to be y as x. Counts from before the push must not be used
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut ys = 0;
+        for _ in 0..2000 {
+            if g.flatten(&mut rng)?.ends_with('y') {
+                ys += 1;
+            }
+        }
+        assert!((800..=1200).contains(&ys), "{} outputs ended with y", ys);
+        Ok(())
+    }
+
+    #[test]
+    fn uniform_derivations_unbounded() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#list#",
+            "list" => ["a", "a#list#"]
+        }?
+        .with_uniform_derivations(true);
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::UnboundedDerivations(key)) if key == "origin"));
+        Ok(())
+    }
+
+    #[test]
+    fn uniform_derivations_empty_ruleset() -> Result<()> {
+        let empty: Vec<&str> = Vec::new();
+        let g = crate::Grammar::from_map(vec![("origin", vec!["x", "#a#"]), ("a", empty)])?
+            .with_uniform_derivations(true);
+        // The rule leading to the empty ruleset is not silently skipped
+        for _ in 0..10 {
+            let res = g.flatten(&mut rand::thread_rng());
+            assert!(matches!(res, Err(Error::EmptyRuleset(key)) if key == "a"));
+        }
+        Ok(())
+    }
+}
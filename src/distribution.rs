@@ -0,0 +1,228 @@
+use rand::{distributions::WeightedIndex, prelude::Distribution as _, seq::SliceRandom, Rng};
+use std::collections::VecDeque;
+
+use crate::Rule;
+
+/// The method used to choose between the rules in a key's ruleset when the
+/// key is expanded.
+///
+/// Every distribution respects the weights of the rules in the ruleset. See
+/// [`Grammar::set_distribution`] for how to set the distribution of a key.
+///
+/// [`Grammar::set_distribution`]: struct.Grammar.html#method.set_distribution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    /// Each expansion chooses a rule at random, independently of any previous
+    /// choices. This is the default
+    Uniform,
+
+    /// Rules are drawn at random without replacement, like cards from a deck,
+    /// so no rule is repeated until every rule has been used. The deck is
+    /// reshuffled once it is empty
+    ShuffleDeck,
+
+    /// Each expansion chooses a rule at random, excluding the rules chosen by
+    /// the given number of previous expansions. At least one rule is always
+    /// left available, so `AvoidLast(1)` never chooses the same rule twice in a
+    /// row for a ruleset with more than one rule
+    AvoidLast(usize),
+
+    /// Each expansion chooses a rule at random, multiplying the weight of each
+    /// rule by the given factor raised to the power of the rule's position in
+    /// the ruleset. A factor less than 1 makes earlier rules more likely to be
+    /// chosen than later ones
+    Falloff(f64),
+}
+
+impl Default for Distribution {
+    fn default() -> Self {
+        Distribution::Uniform
+    }
+}
+
+/// The state kept between expansions of a key by its distribution
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SelectionState {
+    /// The rules remaining in the deck, for `ShuffleDeck`
+    deck: Vec<usize>,
+    /// The most recently chosen rules, newest last, for `AvoidLast`
+    history: VecDeque<usize>,
+}
+
+/// Returns the indices of the rules in a ruleset which can be chosen. Rules
+/// with a weight of zero can only be chosen if every rule has a weight of zero
+pub(crate) fn candidates(rules: &[Rule]) -> Vec<usize> {
+    let candidates: Vec<usize> = (0..rules.len()).filter(|&i| rules[i].weight > 0).collect();
+    if candidates.is_empty() {
+        (0..rules.len()).collect()
+    } else {
+        candidates
+    }
+}
+
+/// Chooses one of the given candidate indices at random, with probability
+/// proportional to its weight. If every candidate has a weight of zero, the
+/// candidates are all equally likely to be chosen
+fn pick<R, F>(candidates: &[usize], weight: F, rng: &mut R) -> Option<usize>
+where
+    R: ?Sized + Rng,
+    F: Fn(usize) -> f64,
+{
+    match WeightedIndex::new(candidates.iter().map(|&i| weight(i))) {
+        Ok(dist) => Some(candidates[dist.sample(rng)]),
+        Err(_) => candidates.choose(rng).copied(),
+    }
+}
+
+impl Distribution {
+    /// Chooses the index of a rule from the given ruleset, updating the
+    /// selection state. Returns `None` if the ruleset is empty
+    pub(crate) fn choose<R>(
+        &self,
+        rules: &[Rule],
+        state: &mut SelectionState,
+        rng: &mut R,
+    ) -> Option<usize>
+    where
+        R: ?Sized + Rng,
+    {
+        let weight = |i: usize| f64::from(rules[i].weight);
+        let mut candidates = candidates(rules);
+
+        match *self {
+            Distribution::Uniform => pick(&candidates, weight, rng),
+            Distribution::ShuffleDeck => {
+                state.deck.retain(|i| candidates.contains(i));
+                if state.deck.is_empty() {
+                    state.deck = candidates;
+                }
+                let choice = pick(&state.deck, weight, rng)?;
+                state.deck.retain(|&i| i != choice);
+                Some(choice)
+            }
+            Distribution::AvoidLast(n) => {
+                let avoid = n.min(candidates.len().saturating_sub(1));
+                let recent: Vec<usize> = state.history.iter().rev().take(avoid).copied().collect();
+                candidates.retain(|i| !recent.contains(i));
+                let choice = pick(&candidates, weight, rng)?;
+                state.history.push_back(choice);
+                while state.history.len() > n {
+                    state.history.pop_front();
+                }
+                Some(choice)
+            }
+            Distribution::Falloff(factor) => {
+                pick(&candidates, |i| weight(i) * factor.powi(i as i32), rng)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Node;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn ruleset(weights: &[u32]) -> Vec<Rule> {
+        weights
+            .iter()
+            .map(|&w| Rule::new(vec![Node::Text(w.to_string())]).with_weight(w))
+            .collect()
+    }
+
+    fn counts(dist: Distribution, rules: &[Rule], n: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut state = SelectionState::default();
+        let mut counts = vec![0; rules.len()];
+        for _ in 0..n {
+            counts[dist.choose(rules, &mut state, &mut rng).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn uniform_weighted() {
+        let counts = counts(Distribution::Uniform, &ruleset(&[1, 0, 9]), 1000);
+        assert_eq!(counts[1], 0);
+        assert!(counts[0] > 50 && counts[0] < 150);
+        assert_eq!(counts[0] + counts[2], 1000);
+    }
+
+    #[test]
+    fn uniform_all_zero_weights() {
+        let counts = counts(Distribution::Uniform, &ruleset(&[0, 0]), 100);
+        assert!(counts[0] > 0 && counts[1] > 0);
+    }
+
+    #[test]
+    fn empty_ruleset() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut state = SelectionState::default();
+        for dist in &[
+            Distribution::Uniform,
+            Distribution::ShuffleDeck,
+            Distribution::AvoidLast(2),
+            Distribution::Falloff(0.5),
+        ] {
+            assert_eq!(dist.choose(&[], &mut state, &mut rng), None);
+        }
+    }
+
+    #[test]
+    fn shuffle_deck() {
+        let rules = ruleset(&[1, 5, 1, 0, 1]);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut state = SelectionState::default();
+        for _ in 0..10 {
+            let mut drawn: Vec<usize> = (0..4)
+                .map(|_| {
+                    Distribution::ShuffleDeck
+                        .choose(&rules, &mut state, &mut rng)
+                        .unwrap()
+                })
+                .collect();
+            drawn.sort_unstable();
+            assert_eq!(drawn, vec![0, 1, 2, 4]);
+        }
+    }
+
+    #[test]
+    fn avoid_last() {
+        let rules = ruleset(&[1, 1, 1, 1]);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut state = SelectionState::default();
+        let drawn: Vec<usize> = (0..100)
+            .map(|_| {
+                Distribution::AvoidLast(2)
+                    .choose(&rules, &mut state, &mut rng)
+                    .unwrap()
+            })
+            .collect();
+        for window in drawn.windows(3) {
+            assert_ne!(window[0], window[1]);
+            assert_ne!(window[0], window[2]);
+            assert_ne!(window[1], window[2]);
+        }
+
+        // Avoiding more rules than there are leaves one rule available
+        let rules = ruleset(&[1, 1]);
+        let drawn: Vec<usize> = (0..100)
+            .map(|_| {
+                Distribution::AvoidLast(5)
+                    .choose(&rules, &mut state, &mut rng)
+                    .unwrap()
+            })
+            .collect();
+        for window in drawn.windows(2) {
+            assert_ne!(window[0], window[1]);
+        }
+    }
+
+    #[test]
+    fn falloff() {
+        let counts = counts(Distribution::Falloff(0.1), &ruleset(&[1, 1, 1]), 1000);
+        assert!(counts[0] > 850);
+        assert!(counts[0] > counts[1] && counts[1] > counts[2]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+
+use crate::{
+    distribution::candidates, tag::Tag, Error, Grammar, MissingKey, Node, Overlay, Result, Rule,
+};
+
+/// A unit of work remaining in a partially expanded output
+#[derive(Debug, Clone)]
+enum Work {
+    /// Expands a node of a rule
+    Node(Node),
+    /// Marks the start of the output of an action or tag
+    Mark,
+    /// Ends an action with the given number of rules, pushing their outputs
+    /// onto the rule stack of the label as a ruleset, if there is a label, and
+    /// otherwise discarding them
+    EndAction(Option<String>, usize),
+    /// Pops a rule off of the rule stack for a key
+    Pop(String),
+    /// Expands a key, branching for each rule that could be chosen
+    Expand(String),
+    /// Ends the expansion of the innermost key
+    Exit,
+    /// Applies the modifiers of a tag to its output
+    EndTag(Tag),
+}
+
+/// A partially expanded output, along with the changes made to the grammar's
+/// rule stacks while producing it
+#[derive(Clone)]
+struct Branch {
+    overlay: Overlay,
+    output: String,
+    /// The work remaining, with the next unit of work last
+    work: Vec<Work>,
+    /// The starting offsets of the actions and tags being expanded
+    marks: Vec<usize>,
+    /// The keys being expanded, along with the ruleset each was expanded from
+    /// and the index of the rule chosen
+    path: Vec<(String, Vec<Rule>, usize)>,
+}
+
+impl Branch {
+    /// Pushes the work to expand the given nodes, in order
+    fn push_nodes(&mut self, nodes: &[Node]) {
+        self.work
+            .extend(nodes.iter().rev().map(|n| Work::Node(n.clone())));
+    }
+
+    /// Pushes the work to execute the given tag
+    fn push_tag(&mut self, tag: &Tag) {
+        if let Some(key) = &tag.key {
+            self.work.push(Work::EndTag(tag.clone()));
+            self.work.push(Work::Expand(key.to_string()));
+            self.work.push(Work::Mark);
+        }
+        for action in tag.actions.iter().rev() {
+            match &action.label {
+                Some(label) if action.is_pop() => self.work.push(Work::Pop(label.clone())),
+                label => {
+                    let rules = &action.rules;
+                    self.work.push(Work::EndAction(label.clone(), rules.len()));
+                    for rule in rules.iter().rev() {
+                        self.push_nodes(&rule.nodes);
+                        self.work.push(Work::Mark);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over every distinct output that a [`Grammar`] can produce for a
+/// key. Created by [`Grammar::enumerate`].
+///
+/// [`Grammar`]: struct.Grammar.html
+/// [`Grammar::enumerate`]: struct.Grammar.html#method.enumerate
+pub struct Enumerate {
+    grammar: Grammar,
+    /// The branches remaining to be explored, with the next branch last
+    branches: Vec<Branch>,
+    /// The outputs produced so far
+    seen: HashSet<String>,
+}
+
+impl Enumerate {
+    pub(crate) fn new(grammar: &Grammar, key: &str) -> Enumerate {
+        Enumerate {
+            grammar: grammar.clone(),
+            branches: vec![Branch {
+                overlay: Overlay::default(),
+                output: String::new(),
+                work: vec![Work::Expand(key.to_string())],
+                marks: Vec::new(),
+                path: Vec::new(),
+            }],
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Expands a branch until it either produces an output, or branches into
+    /// multiple alternatives, in which case they are added to the branches to
+    /// be explored and `None` is returned
+    fn advance(&mut self, mut branch: Branch) -> Result<Option<String>> {
+        let grammar = &self.grammar;
+        while let Some(work) = branch.work.pop() {
+            match work {
+                Work::Node(Node::Text(s)) => branch.output.push_str(&s),
+                Work::Node(Node::Tag(tag)) => branch.push_tag(&tag),
+                Work::Mark => branch.marks.push(branch.output.len()),
+                Work::EndAction(label, len) => {
+                    let mut values = vec![String::new(); len];
+                    for value in values.iter_mut().rev() {
+                        let start = branch.marks.pop().unwrap();
+                        *value = branch.output.split_off(start);
+                    }
+                    if let Some(label) = label {
+                        branch.overlay.push_rule(grammar, label, values);
+                    }
+                }
+                Work::Pop(key) => branch.overlay.pop_rule(grammar, key),
+                Work::Exit => {
+                    branch.path.pop();
+                }
+                Work::EndTag(tag) => {
+                    let start = branch.marks.pop().unwrap();
+                    let modified = tag.apply_modifiers(&branch.output[start..], grammar)?;
+                    branch.output.truncate(start);
+                    branch.output.push_str(&modified);
+                }
+                Work::Expand(key) => {
+                    let rules = match branch.overlay.get_rule(grammar, &key) {
+                        Some(rules) => rules.to_vec(),
+                        None => {
+                            let path = branch.path.iter().map(|(k, _, i)| (k.clone(), *i));
+                            let popped = branch.overlay.was_popped(grammar, &key);
+                            let missing = MissingKey::new(key, path.collect(), popped);
+                            let policy = grammar.missing_key_policy();
+                            let rendered = policy.render(missing)?;
+                            branch.output.push_str(&rendered);
+                            continue;
+                        }
+                    };
+                    if branch.path.iter().any(|(k, r, _)| *k == key && *r == rules) {
+                        let mut cycle: Vec<String> =
+                            branch.path.iter().map(|(k, _, _)| k.clone()).collect();
+                        cycle.push(key);
+                        return Err(Error::InfiniteOutputs(cycle));
+                    }
+                    let mut choices = candidates(&rules);
+                    if choices.is_empty() {
+                        return Err(Error::EmptyRuleset(key));
+                    }
+                    let first = choices.remove(0);
+                    branch.path.push((key, rules.clone(), first));
+                    branch.work.push(Work::Exit);
+
+                    // Explore the alternatives in order, by pushing them in
+                    // reverse, and continue with the first
+                    for &choice in choices.iter().rev() {
+                        let mut alternative = branch.clone();
+                        alternative.path.last_mut().unwrap().2 = choice;
+                        alternative.push_nodes(&rules[choice].nodes);
+                        self.branches.push(alternative);
+                    }
+                    branch.push_nodes(&rules[first].nodes);
+                }
+            }
+        }
+        Ok(Some(branch.output))
+    }
+}
+
+impl Iterator for Enumerate {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(branch) = self.branches.pop() {
+            match self.advance(branch) {
+                Ok(Some(output)) => {
+                    if self.seen.insert(output.clone()) {
+                        return Some(Ok(output));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.branches.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{grammar, Error, Result};
+
+    #[test]
+    fn enumerate_all_outputs() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#a# #b#",
+            "a" => ["x", "y"],
+            "b" => ["1", "2", "1"]
+        }?;
+        let outputs = g.enumerate("origin").collect::<Result<Vec<_>>>()?;
+        assert_eq!(outputs, vec!["x 1", "x 2", "y 1", "y 2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn enumerate_actions_and_modifiers() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#[hero:#name#]story#",
+            "story" => "#hero.capitalize# met #hero#.",
+            "name" => ["ada", "bo"]
+        }?;
+        let outputs = g.enumerate("origin").collect::<Result<Vec<_>>>()?;
+        assert_eq!(outputs, vec!["Ada met ada.", "Bo met bo."]);
+
+        let g = grammar! {
+            "origin" => "#[x:a]y#",
+            "y" => "#x##[x:POP]z#",
+            "z" => "#x#",
+            "x" => ["b", "c"]
+        }?;
+        let outputs = g.enumerate("origin").collect::<Result<Vec<_>>>()?;
+        assert_eq!(outputs, vec!["ab", "ac"]);
+        Ok(())
+    }
+
+    #[test]
+    fn enumerate_skips_zero_weights() -> Result<()> {
+        let g = grammar! {
+            "origin" => [("a", 0), ("b", 1)],
+            "zero" => [("c", 0), ("d", 0)]
+        }?;
+        let outputs = g.enumerate("origin").collect::<Result<Vec<_>>>()?;
+        assert_eq!(outputs, vec!["b"]);
+        let outputs = g.enumerate("zero").collect::<Result<Vec<_>>>()?;
+        assert_eq!(outputs, vec!["c", "d"]);
+        Ok(())
+    }
+
+    #[test]
+    fn enumerate_infinite() -> Result<()> {
+        let g = grammar! {
+            "origin" => ["a", "#list#"],
+            "list" => ["b", "b #list#"]
+        }?;
+        let mut outputs = g.enumerate("origin");
+        assert_eq!(outputs.next().transpose()?, Some("a".to_string()));
+        assert_eq!(outputs.next().transpose()?, Some("b".to_string()));
+        match outputs.next() {
+            Some(Err(Error::InfiniteOutputs(path))) => {
+                assert_eq!(path, vec!["origin", "list", "list"])
+            }
+            other => panic!("expected InfiniteOutputs, got {:?}", other),
+        }
+        assert!(outputs.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn enumerate_missing_key() -> Result<()> {
+        let g = grammar! { "origin" => ["a", "#missing#"] }?;
+        let mut outputs = g.enumerate("origin");
+        assert_eq!(outputs.next().transpose()?, Some("a".to_string()));
+        match outputs.next() {
+            Some(Err(Error::MissingKeyError(missing))) => {
+                assert_eq!(missing.to_string(), "origin[1] -> missing")
+            }
+            other => panic!("expected MissingKeyError, got {:?}", other),
+        }
+        assert!(outputs.next().is_none());
+        Ok(())
+    }
+}
@@ -1,16 +1,215 @@
+use std::fmt;
 use thiserror::Error;
 
+/// Details of an error encountered while parsing a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ParseError {
+    /// The key whose ruleset contains the rule, if known
+    pub key: Option<String>,
+    /// The index of the rule within its ruleset, if known
+    pub index: Option<usize>,
+    /// The byte offset of the error within the rule
+    pub offset: usize,
+    /// The line of the error within the rule, starting at 1
+    pub line: usize,
+    /// The column of the error within its line, in characters, starting at 1
+    pub column: usize,
+    /// The names of the tokens which were expected at the error
+    pub expected: Vec<String>,
+}
+
+impl ParseError {
+    /// Creates a ParseError for an error at the given byte offset in a rule
+    pub(crate) fn new(rule: &str, offset: usize, expected: Vec<String>) -> ParseError {
+        let before = &rule[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        ParseError {
+            key: None,
+            index: None,
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            expected,
+        }
+    }
+
+    /// Sets the key and index of the rule the error was found in
+    pub(crate) fn in_rule<S: Into<String>>(mut self, key: S, index: usize) -> ParseError {
+        self.key = Some(key.into());
+        self.index = Some(index);
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(key) = &self.key {
+            write!(f, "key '{}', ", key)?;
+        }
+        if let Some(index) = self.index {
+            write!(f, "rule {}, ", index)?;
+        }
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        if self.expected.is_empty() {
+            write!(f, "unexpected input")
+        } else {
+            write!(f, "expected {}", self.expected.join(" or "))
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Details of a reference to a key which does not exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MissingKey {
+    /// The missing key
+    pub key: String,
+    /// The keys being expanded when the missing key was referenced, outermost
+    /// first, each with the index of the rule chosen for it
+    pub path: Vec<(String, usize)>,
+    /// Whether the key was defined, but had its last rule removed by `POP`
+    pub popped: bool,
+}
+
+impl MissingKey {
+    pub(crate) fn new<S: Into<String>>(
+        key: S,
+        path: Vec<(String, usize)>,
+        popped: bool,
+    ) -> MissingKey {
+        MissingKey {
+            key: key.into(),
+            path,
+            popped,
+        }
+    }
+}
+
+impl fmt::Display for MissingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, index) in &self.path {
+            write!(f, "{}[{}] -> ", key, index)?;
+        }
+        write!(f, "{}", self.key)?;
+        if self.popped {
+            write!(f, " (removed by POP)")?;
+        }
+        Ok(())
+    }
+}
+
 /// The `tracery` error type
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum Error {
     /// Error encountered while parsing a rule
     #[error("Error while parsing tracery: {0}")]
-    ParseError(String),
+    ParseError(#[from] ParseError),
 
-    /// A referenced key does not exist
+    /// Errors encountered while parsing a grammar, when collecting every parse
+    /// error rather than stopping at the first
+    #[error("Errors while parsing tracery: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    ParseErrors(Vec<ParseError>),
+
+    /// A referenced key does not exist. Contains the key and the path of
+    /// expansions which referenced it
     #[error("Missing key: {0}")]
-    MissingKeyError(String),
+    MissingKeyError(MissingKey),
+
+    /// A key's ruleset has no rules, so the key cannot be expanded. Contains
+    /// the key
+    #[error("Key '{0}' has no rules")]
+    EmptyRuleset(String),
+
+    /// The expansion of a key nested deeper than the grammar's maximum depth.
+    /// Contains the path of keys being expanded, outermost first
+    #[error("Recursion limit exceeded: {}", .0.join(" -> "))]
+    RecursionLimit(Vec<String>),
+
+    /// An execution expanded more keys than allowed by its [`Limits`].
+    /// Contains the limit
+    ///
+    /// [`Limits`]: struct.Limits.html
+    #[error("Exceeded the limit of {0} key expansions")]
+    ExpansionLimit(usize),
+
+    /// An execution ran more actions than allowed by its [`Limits`]. Contains
+    /// the limit
+    ///
+    /// [`Limits`]: struct.Limits.html
+    #[error("Exceeded the limit of {0} actions")]
+    ActionLimit(usize),
+
+    /// An execution pushed more rules than allowed by its [`Limits`].
+    /// Contains the limit
+    ///
+    /// [`Limits`]: struct.Limits.html
+    #[error("Exceeded the limit of {0} rule pushes")]
+    PushLimit(usize),
+
+    /// An execution produced a string longer than allowed by its [`Limits`].
+    /// Contains the limit, in bytes
+    ///
+    /// [`Limits`]: struct.Limits.html
+    #[error("Exceeded the limit of {0} bytes of output")]
+    OutputLimit(usize),
+
+    /// A choice being replayed is out of range for the ruleset of the key
+    /// being expanded
+    #[error("Choice {choice} is out of range for key '{key}', which has {rules} rules")]
+    InvalidChoice {
+        /// The key being expanded
+        key: String,
+        /// The index of the rule which was to be chosen
+        choice: usize,
+        /// The number of rules in the key's ruleset
+        rules: usize,
+    },
+
+    /// A replay ran out of choices before the expansion was finished.
+    /// Contains the key being expanded
+    #[error("Ran out of choices to replay while expanding key '{0}'")]
+    MissingChoice(String),
+
+    /// A replay finished without using all of its choices. Contains the number
+    /// of choices left over
+    #[error("Replay finished with {0} unused choices")]
+    UnusedChoices(usize),
+
+    /// A tag uses a modifier which is not registered with the grammar
+    #[error("Unknown modifier '{modifier}' used on key '{key}'")]
+    UnknownModifier {
+        /// The key of the tag using the modifier
+        key: String,
+        /// The name of the unknown modifier
+        modifier: String,
+    },
+
+    /// An enumeration reached a key from within its own expansion, so the
+    /// grammar can produce infinitely many outputs. Contains the path of keys
+    /// being expanded, outermost first, ending with the repeated key
+    #[error("Grammar can produce infinitely many outputs: {}", .0.join(" -> "))]
+    InfiniteOutputs(Vec<String>),
+
+    /// Derivations were being sampled uniformly, but one of the rules of the
+    /// key being expanded has infinitely many derivations. Contains the key
+    #[error("Cannot sample derivations of key '{0}' uniformly, as it has infinitely many")]
+    UnboundedDerivations(String),
+
+    /// The sink passed to [`Grammar::execute_into`] failed to accept output
+    ///
+    /// [`Grammar::execute_into`]: struct.Grammar.html#method.execute_into
+    #[error("Failed to write output")]
+    FormatError(#[from] std::fmt::Error),
+
+    /// The writer passed to [`Grammar::execute_into_io`] failed
+    ///
+    /// [`Grammar::execute_into_io`]: struct.Grammar.html#method.execute_into_io
+    #[error("I/O error {0}")]
+    IoError(#[from] std::io::Error),
 
     /// Error encountered while parsing JSON input
     #[cfg(feature = "tracery_json")]
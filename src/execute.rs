@@ -1,11 +1,286 @@
+use crate::count::Derivations;
+use crate::trace::Tracer;
+use crate::Error;
 use crate::Grammar;
+use crate::Limits;
+use crate::MissingKey;
+use crate::Overlay;
 use crate::Result;
 
 use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::{fmt, io};
 
 /// A trait for types that can be flattened into an output string
 pub trait Execute {
-    /// Given a grammar and a set of overriden rules (from actions), produces a
-    /// single "flattened" output string or an error
-    fn execute<R: ?Sized + Rng>(&self, grammar: &mut Grammar, rng: &mut R) -> Result<String>;
+    /// Given a grammar and the state of the current execution, including the
+    /// rules pushed and popped by actions, writes a single "flattened" output
+    /// string to the given output, or fails
+    fn execute<R: ?Sized + Rng>(
+        &self,
+        grammar: &Grammar,
+        state: &mut State,
+        out: &mut Output<'_>,
+        rng: &mut R,
+    ) -> Result<()>;
+}
+
+/// A destination for the output of an execution, which counts the bytes
+/// written to it and enforces the limit on output
+pub struct Output<'a> {
+    sink: &'a mut dyn fmt::Write,
+    len: usize,
+    limit: Option<usize>,
+}
+
+impl<'a> Output<'a> {
+    /// Creates an output writing to the given sink, failing if more than
+    /// `limit` bytes are written
+    pub(crate) fn new(sink: &'a mut dyn fmt::Write, limit: Option<usize>) -> Output<'a> {
+        Output {
+            sink,
+            len: 0,
+            limit,
+        }
+    }
+
+    /// Gets the number of bytes written so far
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Writes a fragment of output
+    pub(crate) fn write(&mut self, s: &str) -> Result<()> {
+        self.len += s.len();
+        match self.limit {
+            Some(max) if self.len > max => Err(Error::OutputLimit(max)),
+            _ => Ok(self.sink.write_str(s)?),
+        }
+    }
+}
+
+/// Adapts an [`io::Write`] to be used as an output sink, keeping the I/O
+/// error which [`fmt::Write`] can't report
+pub(crate) struct IoWriter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    error: Option<io::Error>,
+}
+
+impl<'a, W: ?Sized + io::Write> IoWriter<'a, W> {
+    pub(crate) fn new(inner: &'a mut W) -> IoWriter<'a, W> {
+        IoWriter { inner, error: None }
+    }
+
+    /// Takes the error which stopped writing, if any
+    pub(crate) fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+impl<W: ?Sized + io::Write> fmt::Write for IoWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+/// State tracked over the course of a single execution of a Grammar
+#[derive(Debug, Default)]
+pub struct State {
+    /// The keys currently being expanded, outermost first
+    path: Vec<Arc<str>>,
+    /// The index of the rule chosen for each key being expanded
+    choices: Vec<usize>,
+    max_depth: Option<usize>,
+    limits: Limits,
+    expansions: usize,
+    actions: usize,
+    pushes: usize,
+    tracer: Option<Tracer>,
+    /// The choices remaining to be replayed, if replaying
+    replay: Option<VecDeque<usize>>,
+    /// The missing keys rendered by the grammar's missing key policy
+    warnings: Vec<MissingKey>,
+    /// The changes made to the grammar's rule stacks by actions
+    overlay: Overlay,
+    /// The derivations counted when sampling derivations uniformly
+    derivations: Derivations,
+}
+
+/// Increments a counter, failing with the given error if it exceeds the limit
+fn count(counter: &mut usize, limit: Option<usize>, err: fn(usize) -> Error) -> Result<()> {
+    *counter += 1;
+    match limit {
+        Some(max) if *counter > max => Err(err(max)),
+        _ => Ok(()),
+    }
+}
+
+impl State {
+    /// Creates the state for a new execution, enforcing the given maximum
+    /// depth and limits
+    pub(crate) fn new(max_depth: Option<usize>, limits: Limits) -> State {
+        State {
+            max_depth,
+            limits,
+            ..State::default()
+        }
+    }
+
+    /// Enables tracing for this execution
+    pub(crate) fn with_tracer(mut self) -> State {
+        self.tracer = Some(Tracer::new());
+        self
+    }
+
+    /// Gets the tracer for this execution, if tracing is enabled
+    pub(crate) fn tracer(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    /// Gets the changes made to the grammar's rule stacks so far
+    pub(crate) fn overlay(&self) -> &Overlay {
+        &self.overlay
+    }
+
+    /// Gets the changes made to the grammar's rule stacks so far, mutably
+    pub(crate) fn overlay_mut(&mut self) -> &mut Overlay {
+        &mut self.overlay
+    }
+
+    /// Pushes a new ruleset onto the rule stack for a given key, made up of the
+    /// given plain text rules
+    pub(crate) fn push_rule(&mut self, grammar: &Grammar, key: String, rule_strs: Vec<String>) {
+        self.overlay.push_rule(grammar, key, rule_strs);
+        self.derivations.clear();
+    }
+
+    /// Pops a ruleset off the rule stack for a given key
+    pub(crate) fn pop_rule(&mut self, grammar: &Grammar, key: String) {
+        self.overlay.pop_rule(grammar, key);
+        self.derivations.clear();
+    }
+
+    /// Gets the changes made to the grammar's rule stacks so far, along with
+    /// the derivations counted under them
+    pub(crate) fn derivations(&mut self) -> (&Overlay, &mut Derivations) {
+        (&self.overlay, &mut self.derivations)
+    }
+
+    /// Takes the changes made to the grammar's rule stacks, so that they can
+    /// be applied to the grammar
+    pub(crate) fn take_overlay(&mut self) -> Overlay {
+        std::mem::take(&mut self.overlay)
+    }
+
+    /// Takes the tracer for this execution, if tracing is enabled
+    pub(crate) fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Replays the given choices for this execution, instead of choosing rules
+    /// at random
+    pub(crate) fn with_replay(mut self, choices: &[usize]) -> State {
+        self.replay = Some(choices.iter().copied().collect());
+        self
+    }
+
+    /// Takes the next choice for the expansion of a key with the given number
+    /// of rules, if replaying
+    pub(crate) fn replay_choice(&mut self, key: &str, rules: usize) -> Option<Result<usize>> {
+        let replay = self.replay.as_mut()?;
+        Some(match replay.pop_front() {
+            Some(choice) if choice < rules => Ok(choice),
+            Some(choice) => Err(Error::InvalidChoice {
+                key: key.to_string(),
+                choice,
+                rules,
+            }),
+            None => Err(Error::MissingChoice(key.to_string())),
+        })
+    }
+
+    /// Checks that every choice being replayed has been used
+    pub(crate) fn finish_replay(&self) -> Result<()> {
+        match self.replay.as_ref().map(|r| r.len()) {
+            Some(unused) if unused > 0 => Err(Error::UnusedChoices(unused)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the start of the expansion of a key, failing if doing so would
+    /// exceed the maximum depth or the limit on expansions
+    pub(crate) fn enter(&mut self, key: &Arc<str>) -> Result<()> {
+        self.path.push(Arc::clone(key));
+        match self.max_depth {
+            Some(max) if self.path.len() > max => Err(Error::RecursionLimit(
+                self.path.iter().map(|k| k.to_string()).collect(),
+            )),
+            _ => count(
+                &mut self.expansions,
+                self.limits.max_expansions,
+                Error::ExpansionLimit,
+            ),
+        }
+    }
+
+    /// Records the rule chosen for the innermost key
+    pub(crate) fn choose(&mut self, index: usize) {
+        self.choices.push(index);
+    }
+
+    /// Records the end of the expansion of the innermost key
+    pub(crate) fn exit(&mut self) {
+        self.path.pop();
+        // Missing keys end without a rule having been chosen
+        self.choices.truncate(self.path.len());
+    }
+
+    /// Gets the keys being expanded which have had a rule chosen, each with
+    /// the index of the rule chosen for it
+    pub(crate) fn choice_path(&self) -> Vec<(String, usize)> {
+        self.path
+            .iter()
+            .map(|k| k.to_string())
+            .zip(self.choices.iter().copied())
+            .collect()
+    }
+
+    /// Describes the innermost key, which is missing
+    pub(crate) fn missing_key(&self, key: &str, popped: bool) -> MissingKey {
+        MissingKey::new(key, self.choice_path(), popped)
+    }
+
+    /// Records a missing key which was rendered instead of failing
+    pub(crate) fn warn(&mut self, missing: MissingKey) {
+        self.warnings.push(missing);
+    }
+
+    /// Takes the missing keys which were rendered instead of failing
+    pub(crate) fn take_warnings(&mut self) -> Vec<MissingKey> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Records the execution of an action
+    pub(crate) fn count_action(&mut self) -> Result<()> {
+        count(
+            &mut self.actions,
+            self.limits.max_actions,
+            Error::ActionLimit,
+        )
+    }
+
+    /// Records a push onto a rule stack
+    pub(crate) fn count_push(&mut self) -> Result<()> {
+        count(&mut self.pushes, self.limits.max_pushes, Error::PushLimit)
+    }
+
+    /// Gets the maximum length of the output, and of any expansion buffered
+    /// along the way
+    pub(crate) fn max_output_bytes(&self) -> Option<usize> {
+        self.limits.max_output_bytes
+    }
 }
@@ -1,357 +1,1822 @@
 use lazy_static::lazy_static;
-use rand::{seq::SliceRandom, Rng};
-use std::collections::BTreeMap;
-use std::rc::Rc;
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::{fmt, io};
 
-use crate::{parser::parse_str, Error, Execute, Result, Rule};
+use crate::{
+    count::{choose_by_derivations, count_outputs, OutputCount},
+    distribution::SelectionState,
+    execute::IoWriter,
+    modifiers::{without_args, ModifierFn},
+    parser::parse_str,
+    validate::validate,
+    Diagnostic, Distribution, Enumerate, Error, Execute, Limits, MissingKey, MissingKeyPolicy,
+    Output, Overlay, Result, Rule, State, Trace, WeightedRule,
+};
 
 lazy_static! {
     pub(crate) static ref ORIGIN: String = String::from("origin");
 }
 
+/// A rule in a JSON grammar, which is either a plain rule string or an object
+/// of the form `{"rule": "...", "weight": 5}`
+#[cfg(feature = "tracery_json")]
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum JsonRule {
+    Plain(String),
+    Weighted { rule: String, weight: u32 },
+}
+
+#[cfg(feature = "tracery_json")]
+impl From<JsonRule> for WeightedRule {
+    fn from(rule: JsonRule) -> Self {
+        match rule {
+            JsonRule::Plain(rule) => WeightedRule::new(rule, 1),
+            JsonRule::Weighted { rule, weight } => WeightedRule::new(rule, weight),
+        }
+    }
+}
+
+#[cfg(feature = "tracery_json")]
+impl From<WeightedRule> for JsonRule {
+    fn from(WeightedRule { rule, weight }: WeightedRule) -> Self {
+        match weight {
+            1 => JsonRule::Plain(rule),
+            weight => JsonRule::Weighted { rule, weight },
+        }
+    }
+}
+
+/// Parses an input map of keys to rule lists into rule stacks. If
+/// `all_errors` is set, every parse error is collected into an
+/// [`Error::ParseErrors`], otherwise the first is returned as an
+/// [`Error::ParseError`]
+///
+/// [`Error::ParseErrors`]: enum.Error.html#variant.ParseErrors
+/// [`Error::ParseError`]: enum.Error.html#variant.ParseError
+fn parse_map<I, K, C, S>(iter: I, all_errors: bool) -> Result<BTreeMap<String, Vec<Vec<Rule>>>>
+where
+    I: IntoIterator<Item = (K, C)>,
+    K: Into<String>,
+    C: IntoIterator<Item = S>,
+    S: Into<WeightedRule>,
+{
+    let mut map = BTreeMap::new();
+    let mut errors = Vec::new();
+    for (k, v) in iter {
+        let key = k.into();
+        let mut rules = Vec::new();
+        for (index, rule) in v.into_iter().enumerate() {
+            let WeightedRule { rule, weight } = rule.into();
+            match parse_str(rule) {
+                Ok(rule) => rules.push(rule.with_weight(weight)),
+                Err(e) if all_errors => errors.push(e.in_rule(key.as_str(), index)),
+                Err(e) => return Err(e.in_rule(key, index).into()),
+            }
+        }
+        map.insert(key, vec![rules]);
+    }
+    if errors.is_empty() {
+        Ok(map)
+    } else {
+        Err(Error::ParseErrors(errors))
+    }
+}
+
+/// The default maximum depth of nested key expansions, which is low enough
+/// for an execution to fit on the stack of a newly spawned thread, even in an
+/// unoptimized build
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 128;
+
 /// Represents a single, complete tracery grammar.
 ///
 /// See the [`crate-level documentation`] for a usage overview.
 ///
+/// A Grammar is `Send` and `Sync`, so a single grammar can be shared between
+/// threads, and [`flatten`] called from all of them at once. For this reason,
+/// custom modifiers and [`MissingKeyPolicy::fallback`] functions must also be
+/// `Send` and `Sync`.
+///
+/// ```
+/// use std::{sync::Arc, thread};
+/// use tracery::grammar;
+/// # use tracery::Result;
+/// # fn main() -> Result<()> {
+/// let g = Arc::new(grammar! {
+///     "origin" => "#tool# is #description#!",
+///     "tool" => "tracery",
+///     "description" => [ "fun", "awesome" ]
+/// }?);
+/// let handles: Vec<_> = (0..4)
+///     .map(|_| {
+///         let g = Arc::clone(&g);
+///         thread::spawn(move || g.flatten(&mut rand::thread_rng()))
+///     })
+///     .collect();
+/// for handle in handles {
+///     let output = handle.join().unwrap()?;
+///     assert!(output == "tracery is fun!" || output == "tracery is awesome!");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
 /// [`crate-level documentation`]: index.html
+/// [`flatten`]: struct.Grammar.html#method.flatten
+/// [`MissingKeyPolicy::fallback`]: enum.MissingKeyPolicy.html#method.fallback
 #[derive(Clone)]
 pub struct Grammar {
     map: BTreeMap<String, Vec<Vec<Rule>>>,
     default_rule: String,
-    modifier_registry: BTreeMap<String, Rc<dyn Fn(&str) -> String>>,
+    modifier_registry: BTreeMap<String, ModifierFn>,
+    strict_modifiers: bool,
+    max_depth: Option<usize>,
+    limits: Limits,
+    distributions: BTreeMap<String, Distribution>,
+    selection_state: BTreeMap<String, SelectionState>,
+    uniform_derivations: bool,
+    missing_key_policy: MissingKeyPolicy,
+    /// Keys whose last rule has been removed by `POP`
+    popped_keys: BTreeSet<String>,
 }
 
 impl Grammar {
-    pub(crate) fn get_modifier(&self, modifier: &str) -> Option<&dyn Fn(&str) -> String> {
-        self.modifier_registry.get(modifier).map(|x| x.as_ref())
-    }
-
-    /// Pushes a new rule onto the rule stack for a given key
-    pub(crate) fn push_rule(&mut self, key: String, rule_str: String) {
-        use crate::Node;
-        use std::collections::btree_map::Entry;
-        let rule = vec![Rule::new(vec![Node::from(rule_str)])];
-        match self.map.entry(key) {
-            Entry::Occupied(mut occ) => {
-                let stack = occ.get_mut();
-                stack.push(rule);
-            }
-            Entry::Vacant(vac) => {
-                vac.insert(vec![rule]);
-            }
-        }
+    pub(crate) fn get_modifier(&self, modifier: &str) -> Option<&ModifierFn> {
+        self.modifier_registry.get(modifier)
     }
 
-    /// Pops a rule off the rule stack for a given key, removing the key
-    /// entirely if there are no rules left
-    pub(crate) fn pop_rule(&mut self, key: String) {
-        use std::collections::btree_map::Entry;
-        if let Entry::Occupied(mut occ) = self.map.entry(key) {
-            let stack = occ.get_mut();
-            if stack.len() < 2 {
-                occ.remove_entry();
-            } else {
-                stack.pop();
-            }
-        }
+    pub(crate) fn strict_modifiers(&self) -> bool {
+        self.strict_modifiers
     }
 
-    /// Gets a rule with the given key, if it exists
-    pub(crate) fn get_rule(&self, key: &str) -> Option<&Vec<Rule>> {
-        self.map.get(key).and_then(|stack| stack.last())
+    pub(crate) fn max_depth(&self) -> Option<usize> {
+        self.max_depth
     }
 
-    /// Creates a new grammar from a JSON grammar string
+    /// Sets whether unknown modifiers are treated as errors, then returns the
+    /// modified Grammar
     ///
     /// # Examples
     /// ```
-    /// use tracery::Grammar;
+    /// use tracery::{grammar, Error};
     /// # use tracery::Result;
-    /// # use maplit::hashmap;
     /// # fn main() -> Result<()> {
-    /// let json = r##"{
-    ///     "origin": [ "#tool# is #description#!" ],
-    ///     "tool": [ "tracery" ],
-    ///     "description": [ "fun", "awesome" ]
-    /// }"##;
-    /// let g = Grammar::from_json(json)?;
-    /// # let output = g.flatten(&mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
+    /// let g = grammar! {
+    ///     "origin" => "#animal.capitalise#",
+    ///     "animal" => "cat"
+    /// }?.with_strict_modifiers(true);
+    /// let res = g.flatten(&mut rand::thread_rng());
+    /// assert!(matches!(res, Err(Error::UnknownModifier { .. })));
     /// # Ok(())
     /// # }
     /// ```
-    ///
-    /// [`Error`]: enum.Error.html
-    /// [`Grammar`]: struct.Grammar.html
-    #[cfg(feature = "tracery_json")]
-    pub fn from_json<S: AsRef<str>>(s: S) -> Result<Grammar> {
-        let source: BTreeMap<String, Vec<String>> = serde_json::from_str(s.as_ref())?;
-        let mut map: BTreeMap<String, Vec<Vec<Rule>>> = BTreeMap::new();
-        for (key, value) in source.into_iter() {
-            let rules: Vec<Rule> = value.iter().map(parse_str).collect::<Result<Vec<_>>>()?;
-            map.insert(key, vec![rules]);
-        }
-
-        Ok(Grammar {
-            map,
-            default_rule: ORIGIN.clone(),
-            modifier_registry: crate::modifiers::get_default_modifiers(),
-        })
+    pub fn with_strict_modifiers(mut self, strict: bool) -> Grammar {
+        self.set_strict_modifiers(strict);
+        self
     }
 
-    /// Sets a default rule, then returns the modified Grammar
+    /// Sets whether unknown modifiers are treated as errors.
+    ///
+    /// By default, a modifier which is not registered with the Grammar is
+    /// ignored, and the expansion of its tag is left unchanged. In strict
+    /// mode, each execution first checks every rule in the Grammar with
+    /// [`check_modifiers`], and fails with [`Error::UnknownModifier`] if any of
+    /// them uses an unknown modifier, even if that rule would not have been
+    /// chosen. Rules pushed by actions are checked as they are expanded.
     ///
     /// # Examples
     /// ```
     /// use tracery::grammar;
     /// # use tracery::Result;
     /// # fn main() -> Result<()> {
-    /// let g = grammar! {
-    ///     "start" => "#tool# is #description#!",
-    ///     "tool" => "tracery",
-    ///     "description" => [ "fun", "awesome" ]
-    /// }?.with_default_rule("start");
-    /// # let output = g.flatten(&mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
+    /// let mut g = grammar! {
+    ///     "origin" => "#animal.capitalise#",
+    ///     "animal" => "cat"
+    /// }?;
+    /// assert_eq!(g.flatten(&mut rand::thread_rng())?, "cat");
+    ///
+    /// g.set_strict_modifiers(true);
+    /// assert!(g.flatten(&mut rand::thread_rng()).is_err());
     /// # Ok(())
     /// # }
     /// ```
-    pub fn with_default_rule<S: Into<String>>(mut self, s: S) -> Grammar {
-        self.set_default_rule(s);
-        self
+    ///
+    /// [`Error::UnknownModifier`]: enum.Error.html#variant.UnknownModifier
+    /// [`check_modifiers`]: struct.Grammar.html#method.check_modifiers
+    pub fn set_strict_modifiers(&mut self, strict: bool) {
+        self.strict_modifiers = strict;
     }
 
-    /// Sets a default rule
+    /// Checks every rule in the Grammar for modifiers which are not currently
+    /// registered, returning [`Error::UnknownModifier`] for the first one
+    /// found.
+    ///
+    /// This checks against the modifiers registered at the time it is called,
+    /// so it should be called after any custom modifiers have been added.
     ///
     /// # Examples
     /// ```
-    /// use tracery::grammar;
+    /// use tracery::{grammar, Error};
     /// # use tracery::Result;
     /// # fn main() -> Result<()> {
     /// let mut g = grammar! {
-    ///     "start" => "#tool# is #description#!",
-    ///     "tool" => "tracery",
-    ///     "description" => [ "fun", "awesome" ]
+    ///     "origin" => "#animal.possessive#",
+    ///     "animal" => "cat"
     /// }?;
-    /// g.set_default_rule("start");
-    /// # let output = g.flatten(&mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
+    /// assert!(matches!(
+    ///     g.check_modifiers(),
+    ///     Err(Error::UnknownModifier { .. })
+    /// ));
+    ///
+    /// g.add_modifier("possessive", |s: &str| format!("{}'s", s));
+    /// assert!(g.check_modifiers().is_ok());
     /// # Ok(())
     /// # }
     /// ```
-    pub fn set_default_rule<S: Into<String>>(&mut self, s: S) {
-        self.default_rule = s.into();
+    ///
+    /// [`Error::UnknownModifier`]: enum.Error.html#variant.UnknownModifier
+    pub fn check_modifiers(&self) -> Result<()> {
+        self.map
+            .values()
+            .flatten()
+            .flatten()
+            .try_for_each(|rule| rule.try_for_each_tag(&mut |tag| tag.check_modifiers(self)))
     }
 
-    /// Attempts to use the Grammar to produce an output String.
+    /// Checks the Grammar for problems without executing it, returning a
+    /// [`Diagnostic`] for each problem found. An empty list means no problems
+    /// were found.
     ///
-    /// This method clones the Grammar, so any changes made in the course of
-    /// producing an output string (such as pushing a new rule onto a stack
-    /// using a labeled action such as `[foo:bar]`) will be discarded after
-    /// the output is produced.
+    /// This reports:
+    /// - tags referencing keys which are neither defined nor pushed by any
+    ///   action
+    /// - a default rule which is not defined
+    /// - keys which are not reachable from the default rule
+    /// - modifiers which are not currently registered
+    /// - keys with no rules
+    /// - actions popping keys which are never pushed
+    /// - keys containing reserved characters, which no tag can reference
     ///
-    /// If you wish to preserve changes use [`execute`]
+    /// Every rule on every key's rule stack is checked, so validation is most
+    /// useful on a freshly created Grammar.
     ///
     /// # Examples
     /// ```
-    /// use tracery::grammar;
+    /// use tracery::{grammar, Diagnostic};
     /// # use tracery::Result;
     /// # fn main() -> Result<()> {
     /// let g = grammar! {
-    ///     "origin" => "#tool# is #description#!",
-    ///     "tool" => "tracery",
-    ///     "description" => [ "fun", "awesome" ]
+    ///     "origin" => "#[hero:#name#]story#",
+    ///     "story" => "#hero# met #villain.possessive#",
+    ///     "name" => "Mia",
+    ///     "unused" => "nothing"
     /// }?;
-    ///
-    /// // Generate output (either "tracery is fun!" or "tracery is awesome!")
-    /// let output = g.flatten(&mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
+    /// let diagnostics = g.validate();
+    /// assert_eq!(diagnostics.len(), 3);
+    /// assert!(diagnostics.contains(&Diagnostic::UnreachableKey { key: "unused".into() }));
+    /// assert_eq!(
+    ///     diagnostics[0].to_string(),
+    ///     "key 'story' references 'villain', which is never defined"
+    /// );
     /// # Ok(())
     /// # }
     /// ```
     ///
-    /// [`execute`]: struct.Grammar.html#method.execute
-    pub fn flatten<R: ?Sized + Rng>(&self, rng: &mut R) -> Result<String> {
-        self.clone().execute(&self.default_rule, rng)
+    /// [`Diagnostic`]: enum.Diagnostic.html
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        validate(self)
     }
 
-    /// Attempts to use the Grammar to produce an output String, preserving any
-    /// side effects that occur while doing so.
-    ///
-    /// This method produces an output string, but preserves any changes made to
-    /// the Grammar in the course of doing so. For instance, if a labeled action
-    /// such as `[foo:bar]` is executed, then the Grammar will maintain that
-    /// rule after this method returns.
+    /// Registers a modifier under the given name, replacing any existing
+    /// modifier with the same name, including the built-in modifiers. Any
+    /// arguments passed to the modifier in a rule are ignored. To register a
+    /// modifier which accepts arguments, use [`add_modifier_with_args`]
     ///
-    /// If you wish to produce an output String without preserving changes, use
-    /// [`flatten`].
+    /// # Examples
     /// ```
     /// use tracery::grammar;
     /// # use tracery::Result;
     /// # fn main() -> Result<()> {
     /// let mut g = grammar! {
-    ///     "origin" => "#tool# is #description#!",
-    ///     "tool" => "tracery",
-    ///     "description" => [ "fun", "awesome" ]
+    ///     "origin" => "#animal.s.possessive# toys",
+    ///     "animal" => "cat"
     /// }?;
-    ///
-    /// // Generate output (either "tracery is fun!" or "tracery is awesome!")
-    /// let key = String::from("origin");
-    /// let output = g.execute(&key, &mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
+    /// g.add_modifier("possessive", |s: &str| {
+    ///     if s.ends_with('s') {
+    ///         format!("{}'", s)
+    ///     } else {
+    ///         format!("{}'s", s)
+    ///     }
+    /// });
+    /// let output = g.flatten(&mut rand::thread_rng())?;
+    /// assert_eq!(output, "cats' toys");
     /// # Ok(())
     /// # }
     /// ```
     ///
-    /// Using a key created during a previous execution:
+    /// [`add_modifier_with_args`]: struct.Grammar.html#method.add_modifier_with_args
+    pub fn add_modifier<S, F>(&mut self, name: S, modifier: F)
+    where
+        S: Into<String>,
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.modifier_registry
+            .insert(name.into(), without_args(modifier));
+    }
+
+    /// Registers a modifier which accepts arguments under the given name,
+    /// replacing any existing modifier with the same name.
+    ///
+    /// Arguments are given in parentheses after the modifier's name, separated
+    /// by commas, as in `#name.replace(a,e)#`. They are passed to the modifier
+    /// verbatim, including any whitespace. A modifier used without
+    /// parentheses, or with empty parentheses, receives no arguments.
     ///
+    /// # Examples
     /// ```
     /// use tracery::grammar;
     /// # use tracery::Result;
     /// # fn main() -> Result<()> {
-    /// // This time, origin has a side-effect: it creates the rule 'aside'
     /// let mut g = grammar! {
-    ///     "origin" => "#[aside:Rust is, too]tool# is #description#!",
-    ///     "tool" => "tracery",
-    ///     "description" => [ "fun", "awesome" ]
+    ///     "origin" => "#word.repeat(3)#",
+    ///     "word" => "ha"
     /// }?;
-    ///
-    /// // Generate output (either "tracery is fun!" or "tracery is awesome!")
-    /// let key = String::from("origin");
-    /// let output = g.execute(&key, &mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
-    ///
-    /// // The previous call to execute created the 'aside' rule
-    /// let key = String::from("aside");
-    /// // Generates the string "Rust is, too"
-    /// let output = g.execute(&key, &mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "Rust is, too" => true,
-    /// #     _ => false,
-    /// # });
+    /// g.add_modifier_with_args("repeat", |s: &str, args: &[&str]| {
+    ///     let n = args.first().and_then(|n| n.parse().ok()).unwrap_or(1);
+    ///     s.repeat(n)
+    /// });
+    /// let output = g.flatten(&mut rand::thread_rng())?;
+    /// assert_eq!(output, "hahaha");
     /// # Ok(())
     /// # }
     /// ```
-    ///
-    /// [`flatten`]: struct.Grammar.html#method.flatten
-    pub fn execute<R>(&mut self, key: &String, rng: &mut R) -> Result<String>
+    pub fn add_modifier_with_args<S, F>(&mut self, name: S, modifier: F)
     where
-        R: ?Sized + Rng,
+        S: Into<String>,
+        F: Fn(&str, &[&str]) -> String + Send + Sync + 'static,
     {
-        let rule = match self.map.get(key) {
-            Some(rules) => Ok(rules.last().unwrap().choose(rng).unwrap().clone()),
-            None => Err(Error::MissingKeyError(key.clone())),
-        }?;
-        rule.execute(self, rng)
+        self.modifier_registry
+            .insert(name.into(), Arc::new(modifier) as ModifierFn);
     }
 
-    /// Creates a new Grammar from an input map of keys to rule lists
+    /// Registers a modifier which accepts arguments under the given name, then
+    /// returns the modified Grammar
     ///
     /// # Examples
     /// ```
+    /// use tracery::grammar;
     /// # use tracery::Result;
-    /// # use maplit::hashmap;
     /// # fn main() -> Result<()> {
-    /// let map = hashmap! {
-    ///     "origin" => vec![ "#tool# is #description#!" ],
-    ///     "tool" => vec![ "tracery" ],
-    ///     "description" => vec![ "fun", "awesome" ]
-    /// };
-    /// let g = tracery::from_map(map)?;
-    /// # let output = g.flatten(&mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
+    /// let g = grammar! {
+    ///     "origin" => "#title.truncate(5)#",
+    ///     "title" => "tracery"
+    /// }?.with_modifier_with_args("truncate", |s: &str, args: &[&str]| {
+    ///     match args.first().and_then(|n| n.parse().ok()) {
+    ///         Some(n) => s.chars().take(n).collect(),
+    ///         None => s.to_string(),
+    ///     }
+    /// });
+    /// let output = g.flatten(&mut rand::thread_rng())?;
+    /// assert_eq!(output, "trace");
     /// # Ok(())
     /// # }
     /// ```
+    pub fn with_modifier_with_args<S, F>(mut self, name: S, modifier: F) -> Grammar
+    where
+        S: Into<String>,
+        F: Fn(&str, &[&str]) -> String + Send + Sync + 'static,
+    {
+        self.add_modifier_with_args(name, modifier);
+        self
+    }
+
+    /// Registers a modifier under the given name, then returns the modified
+    /// Grammar
     ///
-    /// Any object implementing
-    /// `IntoIterator<Item = (Into<String>, Into<Vec<Into<String>>>)>` will be
-    /// accepted by this function, despite its name:
-    ///
+    /// # Examples
     /// ```
+    /// use tracery::grammar;
     /// # use tracery::Result;
     /// # fn main() -> Result<()> {
-    /// let map = vec![ ("origin", vec![ "#tool# is #description#!" ]),
-    ///                 ("tool", vec![ "tracery" ]),
-    ///                 ("description", vec![ "fun", "awesome" ]) ];
-    /// let g = tracery::from_map(map)?;
-    /// # let output = g.flatten(&mut rand::thread_rng())?;
-    /// # assert!(match output.as_str() {
-    /// #     "tracery is fun!" | "tracery is awesome!" => true,
-    /// #     _ => false,
-    /// # });
+    /// let g = grammar! {
+    ///     "origin" => "#tool.shout#",
+    ///     "tool" => "tracery"
+    /// }?.with_modifier("shout", |s: &str| s.to_uppercase());
+    /// let output = g.flatten(&mut rand::thread_rng())?;
+    /// assert_eq!(output, "TRACERY");
     /// # Ok(())
     /// # }
     /// ```
-    pub fn from_map<I, K, C, S>(iter: I) -> Result<Self>
+    pub fn with_modifier<S, F>(mut self, name: S, modifier: F) -> Grammar
     where
-        I: IntoIterator<Item = (K, C)>,
-        K: Into<String>,
-        C: IntoIterator<Item = S>,
         S: Into<String>,
+        F: Fn(&str) -> String + Send + Sync + 'static,
     {
-        let mut map: BTreeMap<String, Vec<Vec<Rule>>> = BTreeMap::new();
+        self.add_modifier(name, modifier);
+        self
+    }
 
-        for (k, v) in iter {
-            let rules: Vec<Rule> = v
-                .into_iter()
-                .map(|x| parse_str(x.into()))
-                .collect::<Result<Vec<_>>>()?;
-            map.insert(k.into(), vec![rules]);
-        }
+    /// Removes the modifier with the given name, returning whether or not it
+    /// was registered. Tags which use a removed modifier leave their expansion
+    /// unchanged, unless [strict modifiers] are enabled
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#tool.capitalize#",
+    ///     "tool" => "tracery"
+    /// }?;
+    /// assert!(g.remove_modifier("capitalize"));
+    /// let output = g.flatten(&mut rand::thread_rng())?;
+    /// assert_eq!(output, "tracery");
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [strict modifiers]: struct.Grammar.html#method.set_strict_modifiers
+    pub fn remove_modifier(&mut self, name: &str) -> bool {
+        self.modifier_registry.remove(name).is_some()
+    }
 
-        Ok(Grammar {
-            map,
-            default_rule: ORIGIN.clone(),
-            modifier_registry: crate::modifiers::get_default_modifiers(),
-        })
+    /// Returns an iterator over the names of all registered modifiers, in
+    /// sorted order
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#tool#",
+    ///     "tool" => "tracery"
+    /// }?.with_modifier("shout", |s: &str| s.to_uppercase());
+    /// assert!(g.modifiers().any(|name| name == "capitalize"));
+    /// assert!(g.modifiers().any(|name| name == "shout"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn modifiers(&self) -> impl Iterator<Item = &str> {
+        self.modifier_registry.keys().map(|k| k.as_str())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use maplit::hashmap;
+    /// Gets every key with a rule stack
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &String> {
+        self.map.keys()
+    }
 
-    #[test]
-    fn flatten_missing_key() -> Result<()> {
-        let input = hashmap! {
-            "a" => vec![ "a", "aa", "aaa" ]
-        };
-        let g = Grammar::from_map(input)?;
-        let res = g.flatten(&mut rand::thread_rng());
-        assert!(matches!(res, Err(Error::MissingKeyError(_))));
+    /// Gets the key executed by `flatten`
+    pub(crate) fn default_rule(&self) -> &str {
+        &self.default_rule
+    }
 
-        Ok(())
+    /// Gets the whole rule stack for a given key, topmost ruleset last
+    pub(crate) fn rule_stack(&self, key: &str) -> &[Vec<Rule>] {
+        self.map.get(key).map(Vec::as_slice).unwrap_or_default()
     }
 
-    #[test]
-    fn with_default_rule() -> Result<()> {
-        let input = hashmap! {
-            "a" => vec![ "a", "aa", "aaa" ]
-        };
-        let g = Grammar::from_map(input)?.with_default_rule("a");
+    /// Returns whether the given key was removed by popping its last rule
+    pub(crate) fn was_popped(&self, key: &str) -> bool {
+        self.popped_keys.contains(key)
+    }
+
+    /// Gets the selection state kept for a key between executions
+    pub(crate) fn selection_state(&self, key: &str) -> SelectionState {
+        self.selection_state.get(key).cloned().unwrap_or_default()
+    }
+
+    /// Applies the changes made to the rule stacks during an execution
+    fn apply(&mut self, overlay: Overlay) {
+        overlay.apply(
+            &mut self.map,
+            &mut self.popped_keys,
+            &mut self.selection_state,
+        );
+    }
+
+    /// Runs an execution, keeping any changes it makes to the rule stacks,
+    /// even if it fails
+    fn run<R>(
+        &mut self,
+        key: &str,
+        state: &mut State,
+        sink: &mut dyn fmt::Write,
+        rng: &mut R,
+    ) -> Result<()>
+    where
+        R: ?Sized + Rng,
+    {
+        self.check_strict_modifiers()?;
+        let mut out = Output::new(sink, state.max_output_bytes());
+        let result = self.expand(&Arc::from(key), state, &mut out, rng);
+        self.apply(state.take_overlay());
+        result
+    }
+
+    /// Runs an execution into a new string, discarding any changes it makes
+    /// to the rule stacks
+    fn run_to_string<R>(&self, key: &str, state: &mut State, rng: &mut R) -> Result<String>
+    where
+        R: ?Sized + Rng,
+    {
+        self.check_strict_modifiers()?;
+        let mut output = String::new();
+        let mut out = Output::new(&mut output, state.max_output_bytes());
+        self.expand(&Arc::from(key), state, &mut out, rng)?;
+        Ok(output)
+    }
+
+    /// Checks every rule for unknown modifiers before an execution, if the
+    /// Grammar uses strict modifiers, so that a rule which is rarely chosen
+    /// cannot hide one
+    fn check_strict_modifiers(&self) -> Result<()> {
+        if self.strict_modifiers {
+            self.check_modifiers()?;
+        }
+        Ok(())
+    }
+
+    /// Creates a new grammar from a JSON grammar string
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::Grammar;
+    /// # use tracery::Result;
+    /// # use maplit::hashmap;
+    /// # fn main() -> Result<()> {
+    /// let json = r##"{
+    ///     "origin": [ "#tool# is #description#!" ],
+    ///     "tool": [ "tracery" ],
+    ///     "description": [ "fun", "awesome" ]
+    /// }"##;
+    /// let g = Grammar::from_json(json)?;
+    /// # let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Rules can be given weights by using an object of the form
+    /// `{"rule": "...", "weight": 5}` in place of a rule string. Rules given as
+    /// plain strings have a weight of 1:
+    ///
+    /// ```
+    /// use tracery::Grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let json = r##"{
+    ///     "origin": [ "#tool# is #description#!" ],
+    ///     "tool": [ "tracery" ],
+    ///     "description": [ "fun", { "rule": "awesome", "weight": 10 } ]
+    /// }"##;
+    /// let g = Grammar::from_json(json)?;
+    /// # let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Error`]: enum.Error.html
+    /// [`Grammar`]: struct.Grammar.html
+    #[cfg(feature = "tracery_json")]
+    pub fn from_json<S: AsRef<str>>(s: S) -> Result<Grammar> {
+        let source: BTreeMap<String, Vec<JsonRule>> = serde_json::from_str(s.as_ref())?;
+        Grammar::from_map(source)
+    }
+
+    /// Creates a new Grammar from a JSON grammar, like [`from_json`], but
+    /// reports every rule which fails to parse instead of stopping at the
+    /// first.
+    ///
+    /// # Errors
+    /// Returns [`Error::JsonError`] if the input is not valid JSON, or
+    /// [`Error::ParseErrors`] containing an entry for each rule which fails to
+    /// parse, if any do.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{Error, Grammar};
+    /// let json = r##"{
+    ///     "origin": [ "#tool# is #description#!" ],
+    ///     "tool": [ "tracery", "#broken" ],
+    ///     "description": [ "#" ]
+    /// }"##;
+    /// match Grammar::from_json_collect_errors(json) {
+    ///     Err(Error::ParseErrors(errors)) => {
+    ///         let rules: Vec<_> = errors
+    ///             .iter()
+    ///             .map(|e| (e.key.as_deref().unwrap(), e.index.unwrap()))
+    ///             .collect();
+    ///         assert_eq!(rules, vec![ ("description", 0), ("tool", 1) ]);
+    ///     }
+    ///     _ => panic!("expected parse errors"),
+    /// }
+    /// ```
+    ///
+    /// [`from_json`]: struct.Grammar.html#method.from_json
+    /// [`Error::JsonError`]: enum.Error.html#variant.JsonError
+    /// [`Error::ParseErrors`]: enum.Error.html#variant.ParseErrors
+    #[cfg(feature = "tracery_json")]
+    pub fn from_json_collect_errors<S: AsRef<str>>(s: S) -> Result<Grammar> {
+        let source: BTreeMap<String, Vec<JsonRule>> = serde_json::from_str(s.as_ref())?;
+        Grammar::from_map_collect_errors(source)
+    }
+
+    /// Converts the Grammar back into a map of keys to rule lists, which can
+    /// be passed to [`from_map`] to recreate it.
+    ///
+    /// Only the topmost ruleset of each key is included, so rules pushed by
+    /// actions during [`execute`] replace the rules they were pushed over.
+    /// Rule strings are written with any characters that need it escaped, so
+    /// each rule parses back to the same rule, though not necessarily to the
+    /// same string it was parsed from.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Grammar, WeightedRule};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "\\#tracery is #[x:\\]]description.capitalize#",
+    ///     "description" => [ ("fun", 1), ("awesome", 2) ]
+    /// }?;
+    /// let map = g.to_map();
+    /// assert_eq!(map["origin"][0].rule, "\\#tracery is #[x:\\]]description.capitalize#");
+    /// assert_eq!(map["description"][1], WeightedRule::new("awesome", 2));
+    ///
+    /// let copy = Grammar::from_map(map)?;
+    /// assert_eq!(copy.to_map(), g.to_map());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`from_map`]: struct.Grammar.html#method.from_map
+    /// [`execute`]: struct.Grammar.html#method.execute
+    pub fn to_map(&self) -> BTreeMap<String, Vec<WeightedRule>> {
+        self.map
+            .iter()
+            .filter_map(|(key, stack)| stack.last().map(|rules| (key, rules)))
+            .map(|(key, rules)| {
+                let rules = rules
+                    .iter()
+                    .map(|rule| WeightedRule::new(rule.to_string(), rule.weight))
+                    .collect();
+                (key.clone(), rules)
+            })
+            .collect()
+    }
+
+    /// Converts the Grammar back into a JSON grammar, which can be passed to
+    /// [`from_json`] to recreate it. See [`to_map`] for which rules are
+    /// included. Rules with a weight of 1 are written as plain strings, and
+    /// other rules as objects of the form `{"rule": "...", "weight": 5}`.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Grammar};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "\\#tracery is #description#",
+    ///     "description" => [ ("fun", 1), ("awesome", 2) ]
+    /// }?;
+    /// let json = g.to_json()?;
+    /// assert_eq!(
+    ///     json,
+    ///     r##"{"description":["fun",{"rule":"awesome","weight":2}],"origin":["\\#tracery is #description#"]}"##
+    /// );
+    /// assert_eq!(Grammar::from_json(json)?.to_map(), g.to_map());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`from_json`]: struct.Grammar.html#method.from_json
+    /// [`to_map`]: struct.Grammar.html#method.to_map
+    #[cfg(feature = "tracery_json")]
+    pub fn to_json(&self) -> Result<String> {
+        let map: BTreeMap<String, Vec<JsonRule>> = self
+            .to_map()
+            .into_iter()
+            .map(|(key, rules)| (key, rules.into_iter().map(JsonRule::from).collect()))
+            .collect();
+        Ok(serde_json::to_string(&map)?)
+    }
+
+    /// Sets a default rule, then returns the modified Grammar
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "start" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?.with_default_rule("start");
+    /// # let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_default_rule<S: Into<String>>(mut self, s: S) -> Grammar {
+        self.set_default_rule(s);
+        self
+    }
+
+    /// Sets a default rule
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "start" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    /// g.set_default_rule("start");
+    /// # let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_default_rule<S: Into<String>>(&mut self, s: S) {
+        self.default_rule = s.into();
+    }
+
+    /// Sets the distribution used to choose between the rules of the given
+    /// key, then returns the modified Grammar
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Distribution};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#animal#",
+    ///     "animal" => [ "cat", "dog" ]
+    /// }?.with_distribution("animal", Distribution::AvoidLast(1));
+    ///
+    /// let key = String::from("origin");
+    /// let first = g.execute(&key, &mut rand::thread_rng())?;
+    /// let second = g.execute(&key, &mut rand::thread_rng())?;
+    /// assert_ne!(first, second);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_distribution<S: Into<String>>(
+        mut self,
+        key: S,
+        distribution: Distribution,
+    ) -> Grammar {
+        self.set_distribution(key, distribution);
+        self
+    }
+
+    /// Sets the distribution used to choose between the rules of the given
+    /// key. Keys use [`Distribution::Uniform`] by default.
+    ///
+    /// The state of a key's distribution, such as the rules remaining in a
+    /// [`Distribution::ShuffleDeck`], is kept in the Grammar, so it persists
+    /// across calls to [`execute`]. Like other changes made while producing
+    /// output, it is discarded by [`flatten`]. The state is reset whenever the
+    /// distribution is changed, or a rule is pushed onto or popped off of the
+    /// key's rule stack.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Distribution};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#suit#",
+    ///     "suit" => [ "hearts", "diamonds", "clubs", "spades" ]
+    /// }?;
+    /// g.set_distribution("suit", Distribution::ShuffleDeck);
+    ///
+    /// // Each suit is drawn once before any suit is repeated
+    /// let key = String::from("origin");
+    /// let mut suits = (0..4)
+    ///     .map(|_| g.execute(&key, &mut rand::thread_rng()))
+    ///     .collect::<Result<Vec<_>>>()?;
+    /// suits.sort();
+    /// assert_eq!(suits, vec![ "clubs", "diamonds", "hearts", "spades" ]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Distribution::Uniform`]: enum.Distribution.html#variant.Uniform
+    /// [`Distribution::ShuffleDeck`]: enum.Distribution.html#variant.ShuffleDeck
+    /// [`execute`]: struct.Grammar.html#method.execute
+    /// [`flatten`]: struct.Grammar.html#method.flatten
+    pub fn set_distribution<S: Into<String>>(&mut self, key: S, distribution: Distribution) {
+        let key = key.into();
+        self.selection_state.remove(&key);
+        self.distributions.insert(key, distribution);
+    }
+
+    /// Gets the distribution used to choose between the rules of the given key
+    pub fn distribution(&self, key: &str) -> Distribution {
+        self.distributions.get(key).copied().unwrap_or_default()
+    }
+
+    /// Sets whether every derivation is equally likely to be produced, then
+    /// returns the modified Grammar
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => [ "#digit#", "#digit##digit#" ],
+    ///     "digit" => [ "0", "1", "2", "3", "4", "5", "6", "7", "8", "9" ]
+    /// }?.with_uniform_derivations(true);
+    /// let short = (0..1000)
+    ///     .map(|_| g.flatten(&mut rand::thread_rng()))
+    ///     .collect::<Result<Vec<_>>>()?
+    ///     .into_iter()
+    ///     .filter(|s| s.len() == 1)
+    ///     .count();
+    /// // One output in eleven has a single digit
+    /// assert!(short < 250);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_uniform_derivations(mut self, uniform: bool) -> Grammar {
+        self.set_uniform_derivations(uniform);
+        self
+    }
+
+    /// Sets whether every derivation is equally likely to be produced.
+    ///
+    /// By default, each expansion of a key chooses between its rules according
+    /// to their weights and the key's [`Distribution`], so a rule leading to
+    /// few possible outputs is as likely to be chosen as one leading to many.
+    /// When sampling derivations uniformly, the weight of each rule is instead
+    /// multiplied by its number of derivations, as counted by
+    /// [`count_outputs`], and distributions are ignored. With equal weights,
+    /// every derivation of the rule being expanded is then equally likely.
+    ///
+    /// Derivations are counted for each rule as it is expanded, so rules pushed
+    /// by actions are taken into account, but the effect of a rule's actions
+    /// on the rest of the output is not. Counts are reused by later expansions
+    /// in the same execution, until an action changes the rule stacks.
+    ///
+    /// # Errors
+    /// When sampling derivations uniformly, expanding a key fails with
+    /// [`Error::UnboundedDerivations`] if one of its rules has infinitely many
+    /// derivations, with [`Error::MissingKeyError`] if one of its rules refers
+    /// to a key which does not exist, or with [`Error::EmptyRuleset`] if one
+    /// of its rules refers to a key with no rules. These errors occur even if
+    /// the rule leading to them would not have been chosen.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Error};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => [ "#list#" ],
+    ///     "list" => [ "item", "item, #list#" ]
+    /// }?;
+    /// g.set_uniform_derivations(true);
+    /// let res = g.flatten(&mut rand::thread_rng());
+    /// assert!(matches!(res, Err(Error::UnboundedDerivations(_))));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Distribution`]: enum.Distribution.html
+    /// [`count_outputs`]: struct.Grammar.html#method.count_outputs
+    /// [`Error::UnboundedDerivations`]: enum.Error.html#variant.UnboundedDerivations
+    /// [`Error::MissingKeyError`]: enum.Error.html#variant.MissingKeyError
+    /// [`Error::EmptyRuleset`]: enum.Error.html#variant.EmptyRuleset
+    pub fn set_uniform_derivations(&mut self, uniform: bool) {
+        self.uniform_derivations = uniform;
+    }
+
+    /// Sets how references to missing keys are handled, then returns the
+    /// modified Grammar
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, MissingKeyPolicy};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery"
+    /// }?.with_missing_key_policy(MissingKeyPolicy::Parenthesize);
+    /// assert_eq!(g.flatten(&mut rand::thread_rng())?, "tracery is ((description))!");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_missing_key_policy(mut self, policy: MissingKeyPolicy) -> Grammar {
+        self.set_missing_key_policy(policy);
+        self
+    }
+
+    /// Sets how references to missing keys are handled. By default, they fail
+    /// with [`Error::MissingKeyError`]. See [`MissingKeyPolicy`] for the
+    /// alternatives.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, MissingKeyPolicy};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery"
+    /// }?;
+    /// g.set_missing_key_policy(MissingKeyPolicy::Empty);
+    /// assert_eq!(g.flatten(&mut rand::thread_rng())?, "tracery is !");
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Error::MissingKeyError`]: enum.Error.html#variant.MissingKeyError
+    /// [`MissingKeyPolicy`]: enum.MissingKeyPolicy.html
+    pub fn set_missing_key_policy(&mut self, policy: MissingKeyPolicy) {
+        self.missing_key_policy = policy;
+    }
+
+    /// Gets the policy used to handle references to missing keys
+    pub fn missing_key_policy(&self) -> &MissingKeyPolicy {
+        &self.missing_key_policy
+    }
+
+    /// Sets the maximum depth of nested key expansions, then returns the
+    /// modified Grammar
+    ///
+    /// The default maximum depth is 128, which is enough for most grammars
+    /// while fitting on a spawned thread's stack. Grammars which nest keys more
+    /// deeply than that need a higher maximum, or none at all. See
+    /// [`set_max_depth`] for details.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#a#",
+    ///     "a" => "#b#",
+    ///     "b" => "b"
+    /// }?.with_max_depth(Some(2));
+    /// assert!(g.flatten(&mut rand::thread_rng()).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`set_max_depth`]: struct.Grammar.html#method.set_max_depth
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Grammar {
+        self.set_max_depth(max_depth);
+        self
+    }
+
+    /// Sets the maximum depth of nested key expansions.
+    ///
+    /// Each key being expanded, starting with the key passed to [`execute`],
+    /// counts as one level of depth. If an expansion would nest more deeply
+    /// than the maximum, execution fails with [`Error::RecursionLimit`]. This
+    /// protects against grammars which recurse infinitely, such as
+    /// `{"origin": ["#origin#"]}`. The default maximum depth is 128. Passing
+    /// `None` removes the limit entirely.
+    ///
+    /// Each level of depth uses some stack space, and more so in unoptimized
+    /// builds. The default leaves room to execute any grammar on a thread with
+    /// the standard 2 MiB stack, so raising the maximum may require executing
+    /// deeply recursive grammars on threads with larger stacks.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Error};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#origin#"
+    /// }?;
+    /// g.set_max_depth(Some(10));
+    /// let res = g.flatten(&mut rand::thread_rng());
+    /// assert!(matches!(res, Err(Error::RecursionLimit(path)) if path.len() == 11));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`execute`]: struct.Grammar.html#method.execute
+    /// [`Error::RecursionLimit`]: enum.Error.html#variant.RecursionLimit
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
+    }
+
+    /// Sets the limits enforced on each execution, then returns the modified
+    /// Grammar
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Error, Limits};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#a##a##a#",
+    ///     "a" => "a"
+    /// }?.with_limits(Limits {
+    ///     max_expansions: Some(3),
+    ///     ..Limits::default()
+    /// });
+    /// let res = g.flatten(&mut rand::thread_rng());
+    /// assert!(matches!(res, Err(Error::ExpansionLimit(3))));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_limits(mut self, limits: Limits) -> Grammar {
+        self.set_limits(limits);
+        self
+    }
+
+    /// Sets the limits enforced on each execution. See [`Limits`] for
+    /// details. By default, no limits are enforced.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, Error, Limits};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#[a:a][b:b][c:c]a#"
+    /// }?;
+    /// g.set_limits(Limits {
+    ///     max_actions: Some(2),
+    ///     ..Limits::default()
+    /// });
+    /// let res = g.flatten(&mut rand::thread_rng());
+    /// assert!(matches!(res, Err(Error::ActionLimit(2))));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Limits`]: struct.Limits.html
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Gets the limits enforced on each execution
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Attempts to use the Grammar to produce an output String.
+    ///
+    /// Any changes made in the course of producing an output string (such as
+    /// pushing a new rule onto a stack using a labeled action such as
+    /// `[foo:bar]`) are recorded separately from the Grammar, and discarded
+    /// after the output is produced. Only the rule stacks which are changed are
+    /// copied, so the cost of this method does not depend on the size of the
+    /// Grammar.
+    ///
+    /// If you wish to preserve changes use [`execute`]
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    ///
+    /// // Generate output (either "tracery is fun!" or "tracery is awesome!")
+    /// let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`execute`]: struct.Grammar.html#method.execute
+    pub fn flatten<R: ?Sized + Rng>(&self, rng: &mut R) -> Result<String> {
+        let mut state = State::new(self.max_depth, self.limits);
+        self.run_to_string(&self.default_rule, &mut state, rng)
+    }
+
+    /// Produces `n` output Strings in parallel on the [rayon] thread pool, by
+    /// calling [`flatten`] once for each. Requires the `rayon` feature.
+    ///
+    /// Each output is produced with its own random number generator: output
+    /// `i` uses a [`ChaCha12Rng`] seeded with `seed` using [`seed_from_u64`],
+    /// and set to stream `i`. The outputs therefore depend only on `seed`, not
+    /// on the number of threads used. If any output fails, one of the errors
+    /// is returned.
+    ///
+    /// # Examples
+    /// ```
+    /// use rand::SeedableRng;
+    /// use rand_chacha::ChaCha12Rng;
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    /// let outputs = g.flatten_many(1000, 42)?;
+    /// assert_eq!(outputs.len(), 1000);
+    /// assert_eq!(outputs, g.flatten_many(1000, 42)?);
+    ///
+    /// // The same outputs can be produced one at a time
+    /// let mut rng = ChaCha12Rng::seed_from_u64(42);
+    /// rng.set_stream(999);
+    /// assert_eq!(outputs[999], g.flatten(&mut rng)?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [rayon]: https://docs.rs/rayon
+    /// [`flatten`]: struct.Grammar.html#method.flatten
+    /// [`ChaCha12Rng`]: https://docs.rs/rand_chacha/latest/rand_chacha/struct.ChaCha12Rng.html
+    /// [`seed_from_u64`]: https://docs.rs/rand/latest/rand/trait.SeedableRng.html#method.seed_from_u64
+    #[cfg(feature = "rayon")]
+    pub fn flatten_many(&self, n: usize, seed: u64) -> Result<Vec<String>> {
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha12Rng;
+        use rayon::prelude::*;
+
+        (0..n)
+            .into_par_iter()
+            .map(|i| {
+                let mut rng = ChaCha12Rng::seed_from_u64(seed);
+                rng.set_stream(i as u64);
+                self.flatten(&mut rng)
+            })
+            .collect()
+    }
+
+    /// Attempts to use the Grammar to produce an output String, preserving any
+    /// side effects that occur while doing so.
+    ///
+    /// This method produces an output string, but preserves any changes made to
+    /// the Grammar in the course of doing so. For instance, if a labeled action
+    /// such as `[foo:bar]` is executed, then the Grammar will maintain that
+    /// rule after this method returns.
+    ///
+    /// If you wish to produce an output String without preserving changes, use
+    /// [`flatten`].
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    ///
+    /// // Generate output (either "tracery is fun!" or "tracery is awesome!")
+    /// let key = String::from("origin");
+    /// let output = g.execute(&key, &mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Using a key created during a previous execution:
+    ///
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// // This time, origin has a side-effect: it creates the rule 'aside'
+    /// let mut g = grammar! {
+    ///     "origin" => "#[aside:Rust is\\, too]tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    ///
+    /// // Generate output (either "tracery is fun!" or "tracery is awesome!")
+    /// let key = String::from("origin");
+    /// let output = g.execute(&key, &mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    ///
+    /// // The previous call to execute created the 'aside' rule
+    /// let key = String::from("aside");
+    /// // Generates the string "Rust is, too"
+    /// let output = g.execute(&key, &mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "Rust is, too" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`flatten`]: struct.Grammar.html#method.flatten
+    pub fn execute<R>(&mut self, key: &str, rng: &mut R) -> Result<String>
+    where
+        R: ?Sized + Rng,
+    {
+        let mut output = String::new();
+        let mut state = State::new(self.max_depth, self.limits);
+        self.run(key, &mut state, &mut output, rng)?;
+        Ok(output)
+    }
+
+    /// Attempts to use the Grammar to produce an output, preserving any side
+    /// effects that occur while doing so, like [`execute`]. Instead of
+    /// collecting the output into a String, each piece of text is written to
+    /// `out` as soon as it is produced. Only the expansions of tags with
+    /// modifiers are collected first, since modifiers need the whole expansion.
+    ///
+    /// If the execution fails, whatever was written before the failure remains
+    /// in `out`. An error returned by `out` itself produces
+    /// [`Error::FormatError`].
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    ///
+    /// let mut output = String::from("Verdict: ");
+    /// g.execute_into("origin", &mut output, &mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "Verdict: tracery is fun!" | "Verdict: tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`execute`]: struct.Grammar.html#method.execute
+    /// [`Error::FormatError`]: enum.Error.html#variant.FormatError
+    pub fn execute_into<W, R>(&mut self, key: &str, mut out: &mut W, rng: &mut R) -> Result<()>
+    where
+        W: ?Sized + fmt::Write,
+        R: ?Sized + Rng,
+    {
+        let mut state = State::new(self.max_depth, self.limits);
+        self.run(key, &mut state, &mut out, rng)
+    }
+
+    /// Like [`execute_into`], but writes the output to an [`io::Write`], such
+    /// as a file or a socket, as UTF-8. The writer is not flushed, and if it is
+    /// unbuffered, each piece of text is written to it separately, so wrapping
+    /// it in a [`BufWriter`] is recommended. An error returned by `out` stops
+    /// the execution and produces [`Error::IoError`].
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#tool# is #description#!\n",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    ///
+    /// let stdout = std::io::stdout();
+    /// g.execute_into_io("origin", &mut stdout.lock(), &mut rand::thread_rng())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`execute_into`]: struct.Grammar.html#method.execute_into
+    /// [`io::Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+    /// [`BufWriter`]: https://doc.rust-lang.org/std/io/struct.BufWriter.html
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    pub fn execute_into_io<W, R>(&mut self, key: &str, out: &mut W, rng: &mut R) -> Result<()>
+    where
+        W: ?Sized + io::Write,
+        R: ?Sized + Rng,
+    {
+        let mut writer = IoWriter::new(out);
+        let mut state = State::new(self.max_depth, self.limits);
+        let result = self.run(key, &mut state, &mut writer, rng);
+        match writer.take_error() {
+            Some(error) => Err(error.into()),
+            None => result,
+        }
+    }
+
+    /// Attempts to use the Grammar to produce an output String, preserving any
+    /// side effects that occur while doing so, like [`execute`]. Also returns
+    /// the missing keys which were rendered according to the Grammar's
+    /// [`MissingKeyPolicy`], in the order they were encountered.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, MissingKeyPolicy};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#[hero:Mia]story#",
+    ///     "story" => "#hero# and #heroPet#"
+    /// }?.with_missing_key_policy(MissingKeyPolicy::Empty);
+    /// let (output, warnings) = g.execute_with_warnings("origin", &mut rand::thread_rng())?;
+    /// assert_eq!(output, "Mia and ");
+    /// assert_eq!(warnings.len(), 1);
+    /// assert_eq!(warnings[0].to_string(), "origin[0] -> story[0] -> heroPet");
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`execute`]: struct.Grammar.html#method.execute
+    /// [`MissingKeyPolicy`]: enum.MissingKeyPolicy.html
+    pub fn execute_with_warnings<R>(
+        &mut self,
+        key: &str,
+        rng: &mut R,
+    ) -> Result<(String, Vec<MissingKey>)>
+    where
+        R: ?Sized + Rng,
+    {
+        let mut output = String::new();
+        let mut state = State::new(self.max_depth, self.limits);
+        self.run(key, &mut state, &mut output, rng)?;
+        Ok((output, state.take_warnings()))
+    }
+
+    /// Attempts to use the Grammar to produce an output String, preserving any
+    /// side effects that occur while doing so, like [`execute`]. Also returns
+    /// a tree recording each expansion which produced the output.
+    ///
+    /// Each [`TraceNode`] in the tree records the key which was expanded, the
+    /// index of the rule chosen from the key's ruleset, the actions run, the
+    /// modifiers applied, and the range of bytes in the output that the
+    /// expansion covers.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let mut g = grammar! {
+    ///     "origin" => "#tool# is #description.capitalize#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    ///
+    /// let trace = g.execute_traced("origin", &mut rand::thread_rng())?;
+    /// let description = &trace.root.children[1];
+    /// assert_eq!(description.key, "description");
+    /// assert_eq!(description.modifiers, vec!["capitalize"]);
+    /// assert_eq!(
+    ///     &trace.output[description.range.clone()],
+    ///     ["Fun", "Awesome"][description.choice]
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`execute`]: struct.Grammar.html#method.execute
+    /// [`TraceNode`]: struct.TraceNode.html
+    pub fn execute_traced<R>(&mut self, key: &str, rng: &mut R) -> Result<Trace>
+    where
+        R: ?Sized + Rng,
+    {
+        let mut output = String::new();
+        let mut state = State::new(self.max_depth, self.limits).with_tracer();
+        self.run(key, &mut state, &mut output, rng)?;
+        Ok(state.take_tracer().unwrap().finish(output))
+    }
+
+    /// Reproduces an output of the Grammar from the sequence of rules chosen
+    /// while producing it, without using an RNG.
+    ///
+    /// `choices` contains the index of the rule chosen from the key's ruleset
+    /// for each key expansion, in the order the expansions occurred, as
+    /// recorded in [`Trace::choices`] by [`execute_traced`]. Since each choice
+    /// is an index into a single key's ruleset, a sequence of choices remains
+    /// valid as long as the rulesets of the keys it expands are unchanged,
+    /// even if other keys are added to or removed from the Grammar.
+    ///
+    /// Like [`flatten`], this method discards any changes made while producing
+    /// the output. It fails with
+    /// [`Error::InvalidChoice`] if a choice is out of range,
+    /// [`Error::MissingChoice`] if there are too few choices, and
+    /// [`Error::UnusedChoices`] if there are too many.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    /// assert_eq!(g.replay("origin", &[0, 0, 1])?, "tracery is awesome!");
+    ///
+    /// let trace = g.clone().execute_traced("origin", &mut rand::thread_rng())?;
+    /// assert_eq!(g.replay("origin", &trace.choices)?, trace.output);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Trace::choices`]: struct.Trace.html#structfield.choices
+    /// [`execute_traced`]: struct.Grammar.html#method.execute_traced
+    /// [`flatten`]: struct.Grammar.html#method.flatten
+    /// [`Error::InvalidChoice`]: enum.Error.html#variant.InvalidChoice
+    /// [`Error::MissingChoice`]: enum.Error.html#variant.MissingChoice
+    /// [`Error::UnusedChoices`]: enum.Error.html#variant.UnusedChoices
+    pub fn replay(&self, key: &str, choices: &[usize]) -> Result<String> {
+        // The RNG is never used, since every choice comes from the replay
+        let mut rng = rand::rngs::mock::StepRng::new(0, 0);
+        let mut state = State::new(self.max_depth, self.limits).with_replay(choices);
+        let output = self.run_to_string(key, &mut state, &mut rng)?;
+        state.finish_replay()?;
+        Ok(output)
+    }
+
+    /// Returns an iterator over every distinct string that this grammar can
+    /// produce for the given key, without modifying the grammar.
+    ///
+    /// Outputs are produced lazily, in the order of the rules chosen to produce
+    /// them. Each alternative keeps its own record of the rules pushed and
+    /// popped while producing it, so actions are taken into account. Rules with a
+    /// weight of zero are never explored, unless every rule for their key has a
+    /// weight of zero.
+    ///
+    /// If a key is reached from within its own expansion, using the same
+    /// ruleset, the grammar can produce infinitely many outputs. The iterator
+    /// then yields an [`Error::InfiniteOutputs`] and stops. Any other error,
+    /// such as a missing key under the default [`MissingKeyPolicy`], also ends
+    /// the iteration.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::grammar;
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => "tracery",
+    ///     "description" => [ "fun", "awesome" ]
+    /// }?;
+    /// let outputs = g.enumerate("origin").collect::<Result<Vec<_>>>()?;
+    /// assert_eq!(outputs, vec!["tracery is fun!", "tracery is awesome!"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Error::InfiniteOutputs`]: enum.Error.html#variant.InfiniteOutputs
+    /// [`MissingKeyPolicy`]: enum.MissingKeyPolicy.html
+    pub fn enumerate(&self, key: &str) -> Enumerate {
+        Enumerate::new(self, key)
+    }
+
+    /// Counts the number of distinct derivations this grammar can produce for
+    /// the given key, under the current rule stacks.
+    ///
+    /// The count is found by analyzing the parsed rules, rather than by
+    /// executing them. Each choice of rule for each expanded key makes a
+    /// distinct derivation, even if two derivations produce the same string.
+    /// Actions are taken into account: a pushed rule has exactly one
+    /// derivation, and a popped rule reveals the rule beneath it. Rules with a
+    /// weight of zero are not counted, unless every rule for their key has a
+    /// weight of zero. [`Limits`] are not taken into account, but the maximum
+    /// depth is, as executing a derivation nested more deeply would fail.
+    ///
+    /// If a key can be reached from within its own expansion, using the same
+    /// ruleset, the grammar is recursive and [`OutputCount::Unbounded`] is
+    /// returned.
+    ///
+    /// # Errors
+    /// Returns [`Error::MissingKeyError`] if a key which could be expanded
+    /// does not exist. If the Grammar's [`MissingKeyPolicy`] renders missing
+    /// keys instead, each missing key has exactly one derivation. Returns
+    /// [`Error::RecursionLimit`] if a key could be expanded more deeply than
+    /// the maximum depth set by [`set_max_depth`]. Returns
+    /// [`Error::EmptyRuleset`] if a key which could be expanded has no rules,
+    /// as executing that derivation would fail.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{grammar, OutputCount};
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let g = grammar! {
+    ///     "origin" => "#tool# is #description#!",
+    ///     "tool" => [ "tracery", "tracery-rs" ],
+    ///     "description" => [ "fun", "awesome", "neat" ],
+    ///     "list" => [ "#description#", "#description# and #list#" ]
+    /// }?;
+    /// assert_eq!(g.count_outputs("origin")?, OutputCount::Finite(6u32.into()));
+    /// assert_eq!(g.count_outputs("list")?, OutputCount::Unbounded);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Limits`]: struct.Limits.html
+    /// [`MissingKeyPolicy`]: enum.MissingKeyPolicy.html
+    /// [`OutputCount::Unbounded`]: enum.OutputCount.html#variant.Unbounded
+    /// [`Error::MissingKeyError`]: enum.Error.html#variant.MissingKeyError
+    /// [`Error::RecursionLimit`]: enum.Error.html#variant.RecursionLimit
+    /// [`Error::EmptyRuleset`]: enum.Error.html#variant.EmptyRuleset
+    /// [`set_max_depth`]: struct.Grammar.html#method.set_max_depth
+    pub fn count_outputs(&self, key: &str) -> Result<OutputCount> {
+        count_outputs(self, key)
+    }
+
+    /// Renders a missing key according to the missing key policy, as part of
+    /// an ongoing execution
+    fn render_missing(&self, key: &str, state: &mut State, out: &mut Output<'_>) -> Result<()> {
+        let popped = state.overlay().was_popped(self, key);
+        let missing = state.missing_key(key, popped);
+        let output = self.missing_key_policy.render(missing.clone())?;
+        state.warn(missing);
+        out.write(&output)?;
+        if let Some(tracer) = state.tracer() {
+            tracer.missing(key, output.len());
+        }
+        state.exit();
+        Ok(())
+    }
+
+    /// Chooses the index of the rule to expand from the given ruleset, the
+    /// topmost for a key, as part of an ongoing execution. Kept apart from
+    /// [`expand`], which is on the stack once for every level of nesting, so
+    /// that its stack frame stays small
+    ///
+    /// [`expand`]: #method.expand
+    fn choose_rule<R>(
+        &self,
+        key: &str,
+        rules: &[Rule],
+        state: &mut State,
+        rng: &mut R,
+    ) -> Result<usize>
+    where
+        R: ?Sized + Rng,
+    {
+        if rules.is_empty() {
+            return Err(Error::EmptyRuleset(key.to_string()));
+        }
+        match state.replay_choice(key, rules.len()) {
+            Some(choice) => choice,
+            None if self.uniform_derivations => {
+                let path = state.choice_path();
+                let (overlay, derivations) = state.derivations();
+                choose_by_derivations(self, overlay, derivations, key, rules, path, rng)
+            }
+            None => state
+                .overlay_mut()
+                .choose(self, key, rules, rng)
+                .ok_or_else(|| Error::EmptyRuleset(key.to_string())),
+        }
+    }
+
+    /// Expands the given key as part of an ongoing execution, writing the
+    /// expansion to the given output
+    pub(crate) fn expand<R>(
+        &self,
+        key: &Arc<str>,
+        state: &mut State,
+        out: &mut Output<'_>,
+        rng: &mut R,
+    ) -> Result<()>
+    where
+        R: ?Sized + Rng,
+    {
+        state.enter(key)?;
+        let rules = match state.overlay().get_rule(self, key) {
+            Some(rules) => rules,
+            None => return self.render_missing(key, state, out),
+        };
+        let index = self.choose_rule(key, &rules, state, rng)?;
+        let rule = &rules[index];
+        state.choose(index);
+        if let Some(tracer) = state.tracer() {
+            tracer.choose(index);
+            tracer.begin_rule();
+        }
+        let start = out.len();
+        rule.execute(self, state, out, rng)?;
+        if let Some(tracer) = state.tracer() {
+            tracer.end_expansion(key, index, out.len() - start);
+        }
+        state.exit();
+        Ok(())
+    }
+
+    /// Creates a new Grammar from an input map of keys to rule lists
+    ///
+    /// # Examples
+    /// ```
+    /// # use tracery::Result;
+    /// # use maplit::hashmap;
+    /// # fn main() -> Result<()> {
+    /// let map = hashmap! {
+    ///     "origin" => vec![ "#tool# is #description#!" ],
+    ///     "tool" => vec![ "tracery" ],
+    ///     "description" => vec![ "fun", "awesome" ]
+    /// };
+    /// let g = tracery::from_map(map)?;
+    /// # let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Any object implementing
+    /// `IntoIterator<Item = (Into<String>, Into<Vec<Into<String>>>)>` will be
+    /// accepted by this function, despite its name:
+    ///
+    /// ```
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let map = vec![ ("origin", vec![ "#tool# is #description#!" ]),
+    ///                 ("tool", vec![ "tracery" ]),
+    ///                 ("description", vec![ "fun", "awesome" ]) ];
+    /// let g = tracery::from_map(map)?;
+    /// # let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Rules can be given weights by passing `(rule, weight)` tuples or
+    /// [`WeightedRule`]s in place of strings. Rules given as plain strings have
+    /// a weight of 1:
+    ///
+    /// ```
+    /// # use tracery::Result;
+    /// # fn main() -> Result<()> {
+    /// let map = vec![ ("origin", vec![ ("#tool# is #description#!", 1) ]),
+    ///                 ("tool", vec![ ("tracery", 1) ]),
+    ///                 ("description", vec![ ("fun", 1), ("awesome", 10) ]) ];
+    /// let g = tracery::from_map(map)?;
+    /// # let output = g.flatten(&mut rand::thread_rng())?;
+    /// # assert!(match output.as_str() {
+    /// #     "tracery is fun!" | "tracery is awesome!" => true,
+    /// #     _ => false,
+    /// # });
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`WeightedRule`]: struct.WeightedRule.html
+    pub fn from_map<I, K, C, S>(iter: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, C)>,
+        K: Into<String>,
+        C: IntoIterator<Item = S>,
+        S: Into<WeightedRule>,
+    {
+        let map = parse_map(iter, false)?;
+        Ok(Grammar::new(map))
+    }
+
+    /// Creates a new Grammar from an input map of keys to rule lists, like
+    /// [`from_map`], but reports every rule which fails to parse instead of
+    /// stopping at the first.
+    ///
+    /// # Errors
+    /// Returns [`Error::ParseErrors`] containing an entry for each rule which
+    /// fails to parse, if any do.
+    ///
+    /// # Examples
+    /// ```
+    /// use tracery::{Error, Grammar};
+    /// let map = vec![ ("origin", vec![ "#tool# is fun", "#tool.# is fun" ]),
+    ///                 ("tool", vec![ "#tracery" ]) ];
+    /// match Grammar::from_map_collect_errors(map) {
+    ///     Err(Error::ParseErrors(errors)) => {
+    ///         assert_eq!(errors.len(), 2);
+    ///         assert_eq!(errors[0].key.as_deref(), Some("origin"));
+    ///         assert_eq!(errors[0].index, Some(1));
+    ///         assert_eq!(errors[0].column, 7);
+    ///         assert_eq!(errors[0].expected, vec![ "modifier_name" ]);
+    ///         assert_eq!(errors[1].key.as_deref(), Some("tool"));
+    ///     }
+    ///     _ => panic!("expected parse errors"),
+    /// }
+    /// ```
+    ///
+    /// [`from_map`]: struct.Grammar.html#method.from_map
+    /// [`Error::ParseErrors`]: enum.Error.html#variant.ParseErrors
+    pub fn from_map_collect_errors<I, K, C, S>(iter: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, C)>,
+        K: Into<String>,
+        C: IntoIterator<Item = S>,
+        S: Into<WeightedRule>,
+    {
+        Ok(Grammar::new(parse_map(iter, true)?))
+    }
+
+    /// Creates a Grammar with default settings from parsed rule stacks
+    fn new(map: BTreeMap<String, Vec<Vec<Rule>>>) -> Grammar {
+        Grammar {
+            map,
+            default_rule: ORIGIN.clone(),
+            modifier_registry: crate::modifiers::get_default_modifiers(),
+            strict_modifiers: false,
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            limits: Limits::default(),
+            distributions: BTreeMap::new(),
+            selection_state: BTreeMap::new(),
+            uniform_derivations: false,
+            missing_key_policy: MissingKeyPolicy::default(),
+            popped_keys: BTreeSet::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar;
+    use maplit::hashmap;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn flatten_missing_key() -> Result<()> {
+        let input = hashmap! {
+            "a" => vec![ "a", "aa", "aaa" ]
+        };
+        let g = Grammar::from_map(input)?;
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::MissingKeyError(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_default_rule() -> Result<()> {
+        let input = hashmap! {
+            "a" => vec![ "a", "aa", "aaa" ]
+        };
+        let g = Grammar::from_map(input)?.with_default_rule("a");
+        let res = g.flatten(&mut rand::thread_rng())?;
+        assert_eq!(res.chars().next().unwrap(), 'a');
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_default_rule() -> Result<()> {
+        let input = hashmap! {
+            "a" => vec![ "a", "aa", "aaa" ]
+        };
+        let mut g = Grammar::from_map(input)?;
+        g.set_default_rule("a");
+        let res = g.flatten(&mut rand::thread_rng())?;
+        assert_eq!(res.chars().next().unwrap(), 'a');
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "tracery_json")]
+    fn from_json() -> Result<()> {
+        let x = r#"{
+            "origin": [ "a", "aa" ]
+        }"#;
+        let g = Grammar::from_json(x)?;
         let res = g.flatten(&mut rand::thread_rng())?;
         assert_eq!(res.chars().next().unwrap(), 'a');
 
@@ -359,89 +1824,733 @@ mod tests {
     }
 
     #[test]
-    fn set_default_rule() -> Result<()> {
+    #[cfg(feature = "tracery_json")]
+    fn from_json_weighted() -> Result<()> {
+        let x = r#"{
+            "origin": [ "a", { "rule": "b", "weight": 0 }, { "rule": "c", "weight": 2 } ]
+        }"#;
+        let g = Grammar::from_json(x)?;
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            assert_ne!("b", g.flatten(&mut rng)?);
+        }
+
+        let x = r#"{ "origin": [ { "rule": "b", "weight": -1 } ] }"#;
+        assert!(matches!(Grammar::from_json(x), Err(Error::JsonError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn weights_survive_push_and_pop() -> Result<()> {
+        let input = vec![
+            (
+                "origin",
+                vec![("#coin# #[coin:edge]coin# #[coin:POP]coin#", 1)],
+            ),
+            ("coin", vec![("heads", 1), ("tails", 0)]),
+        ];
+        let mut g = Grammar::from_map(input)?;
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            assert_eq!("heads edge heads", g.execute("origin", &mut rng)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn execute() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#[foo:bar]baz#"],
+            "baz" => vec!["baz"]
+        };
+        let mut grammar = Grammar::from_map(input)?;
+
+        // The first invocation should produce the string baz from the rule baz
+        let origin = String::from("origin");
+        assert_eq!("baz", grammar.execute(&origin, &mut rand::thread_rng())?);
+
+        // It should have also produced a new rule foo with the value bar
+        let origin = String::from("foo");
+        assert_eq!("bar", grammar.execute(&origin, &mut rand::thread_rng())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn execute_function() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#setFoo##baz#"],
+            "setFoo" => vec!["[foo:bar][bar:#[qux:quux]baz#]"],
+            "baz" => vec!["baz"]
+        };
+        let mut grammar = Grammar::from_map(input)?;
+
+        // The first invocation should produce the string baz from the rule baz
+        let origin = String::from("origin");
+        assert_eq!("baz", grammar.execute(&origin, &mut rand::thread_rng())?);
+
+        // It should have also produced a new rule foo with the value bar
+        let origin = String::from("foo");
+        assert_eq!("bar", grammar.execute(&origin, &mut rand::thread_rng())?);
+
+        // ..and a new rule bar with the value baz
+        let origin = String::from("bar");
+        assert_eq!("baz", grammar.execute(&origin, &mut rand::thread_rng())?);
+
+        // ..aaaand a new rule qux with the value quux
+        let origin = String::from("qux");
+        assert_eq!("quux", grammar.execute(&origin, &mut rand::thread_rng())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pop_rule() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#[foo:baz]foo##[foo:POP]foo#"],
+            "foo" => vec!["bar"]
+        };
+        let mut grammar = Grammar::from_map(input)?;
+        assert_eq!(
+            "bazbar",
+            grammar.execute(&String::from("origin"), &mut rand::thread_rng())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn flatten_many_is_deterministic() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#a##a##a#",
+            "a" => ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]
+        }?;
+        let outputs = g.flatten_many(500, 7)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        assert_eq!(pool.install(|| g.flatten_many(500, 7))?, outputs);
+        assert_ne!(g.flatten_many(500, 8)?, outputs);
+
+        let distinct: BTreeSet<&String> = outputs.iter().collect();
+        assert!(distinct.len() > 250);
+
+        let g = grammar! { "origin" => "#missing#" }?;
+        assert!(matches!(
+            g.flatten_many(4, 7),
+            Err(Error::MissingKeyError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn flatten_leaves_grammar_unchanged() -> Result<()> {
+        let mut g = grammar! {
+            "origin" => "[hero:Mia][hero:POP][animal:POP][hero:Arjun]#animal#",
+            "animal" => "owl"
+        }?;
+        assert!(g.flatten(&mut rand::thread_rng()).is_err());
+        assert_eq!(g.to_map()["animal"].len(), 1);
+        assert!(!g.to_map().contains_key("hero"));
+
+        // Changes made by execute are kept, even if it fails
+        assert!(g.execute("origin", &mut rand::thread_rng()).is_err());
+        assert!(!g.to_map().contains_key("animal"));
+        assert!(g.was_popped("animal"));
+        assert_eq!(g.to_map()["hero"][0].rule, "Arjun");
+        Ok(())
+    }
+
+    #[test]
+    fn action_only_tag() -> Result<()> {
+        let mut g = grammar! {
+            "origin" => "#[hero:#name#]#I am #hero#.",
+            "name" => "Mia"
+        }?;
+        assert_eq!(g.execute("origin", &mut rand::thread_rng())?, "I am Mia.");
+        Ok(())
+    }
+
+    #[test]
+    fn push_multiple_values() -> Result<()> {
+        let g = grammar! {
+            "origin" => "[animal:cat,#bird#,owl\\,ish]#animal#",
+            "bird" => "duck"
+        }?;
+        let outputs: BTreeSet<String> = g.enumerate("origin").collect::<Result<_>>()?;
+        let expected = ["cat", "duck", "owl,ish"];
+        assert_eq!(outputs, expected.iter().map(|s| s.to_string()).collect());
+        assert_eq!(
+            g.count_outputs("origin")?,
+            OutputCount::Finite(crate::BigUint::from(3u32))
+        );
+
+        let mut g = g;
+        g.execute("origin", &mut rand::thread_rng())?;
+        let pushed = g.rule_stack("animal").last().unwrap();
+        assert_eq!(pushed.len(), 3);
+        assert!(expected.contains(&g.execute("animal", &mut rand::thread_rng())?.as_str()));
+        Ok(())
+    }
+
+    #[test]
+    fn custom_modifier() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#animal.s.possessive.capitalize# toys"],
+            "animal" => vec!["cat"]
+        };
+        let g = Grammar::from_map(input)?.with_modifier("possessive", |s: &str| {
+            if s.ends_with('s') {
+                format!("{}'", s)
+            } else {
+                format!("{}'s", s)
+            }
+        });
+        assert_eq!("Cats' toys", g.flatten(&mut rand::thread_rng())?);
+        Ok(())
+    }
+
+    #[test]
+    fn override_and_remove_modifier() -> Result<()> {
         let input = hashmap! {
-            "a" => vec![ "a", "aa", "aaa" ]
+            "origin" => vec!["#animal.s.capitalize#"],
+            "animal" => vec!["cat"]
         };
         let mut g = Grammar::from_map(input)?;
-        g.set_default_rule("a");
-        let res = g.flatten(&mut rand::thread_rng())?;
-        assert_eq!(res.chars().next().unwrap(), 'a');
+        g.add_modifier("s", |s: &str| format!("{}z", s));
+        assert_eq!("Catz", g.flatten(&mut rand::thread_rng())?);
 
+        assert!(g.remove_modifier("capitalize"));
+        assert!(!g.remove_modifier("capitalize"));
+        assert!(!g.modifiers().any(|name| name == "capitalize"));
+        assert_eq!("catz", g.flatten(&mut rand::thread_rng())?);
         Ok(())
     }
 
     #[test]
-    #[cfg(feature = "tracery_json")]
-    fn from_json() -> Result<()> {
-        let x = r#"{
-            "origin": [ "a", "aa" ]
-        }"#;
-        let g = Grammar::from_json(x)?;
-        let res = g.flatten(&mut rand::thread_rng())?;
-        assert_eq!(res.chars().next().unwrap(), 'a');
+    fn modifier_with_args() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#animal.repeat(2).s.capitalize# #animal.replace(a,o)# #animal.repeat#"],
+            "animal" => vec!["cat"]
+        };
+        let g = Grammar::from_map(input)?.with_modifier_with_args(
+            "repeat",
+            |s: &str, args: &[&str]| {
+                let n = args.first().and_then(|n| n.parse().ok()).unwrap_or(1);
+                s.repeat(n)
+            },
+        );
+        assert_eq!("Catcats cot cat", g.flatten(&mut rand::thread_rng())?);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_modifiers() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#animal.capitalise#"],
+            "animal" => vec!["cat"]
+        };
+        let mut g = Grammar::from_map(input)?;
+        assert_eq!("cat", g.flatten(&mut rand::thread_rng())?);
+
+        g.set_strict_modifiers(true);
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(
+            res,
+            Err(Error::UnknownModifier { key, modifier }) if key == "animal" && modifier == "capitalise"
+        ));
 
+        g.add_modifier("capitalise", |s: &str| s.to_uppercase());
+        assert_eq!("CAT", g.flatten(&mut rand::thread_rng())?);
         Ok(())
     }
 
     #[test]
-    fn execute() -> Result<()> {
+    fn strict_modifiers_in_unchosen_rule() -> Result<()> {
         let input = hashmap! {
-            "origin" => vec!["#[foo:bar]baz#"],
-            "baz" => vec!["baz"]
+            "origin" => vec![WeightedRule::new("#animal#", 1), WeightedRule::new("#rare#", 0)],
+            "animal" => vec![WeightedRule::new("cat", 1)],
+            "rare" => vec![WeightedRule::new("#animal.capitalise#", 1)]
         };
-        let mut grammar = Grammar::from_map(input)?;
+        let mut g = Grammar::from_map(input)?;
+        assert_eq!("cat", g.flatten(&mut rand::thread_rng())?);
+        assert!(g.validate().contains(&Diagnostic::UnknownModifier {
+            modifier: "capitalise".into(),
+            in_key: "rare".into(),
+        }));
 
-        // The first invocation should produce the string baz from the rule baz
-        let origin = String::from("origin");
-        assert_eq!("baz", grammar.execute(&origin, &mut rand::thread_rng())?);
+        g.set_strict_modifiers(true);
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(
+            res,
+            Err(Error::UnknownModifier { key, modifier }) if key == "animal" && modifier == "capitalise"
+        ));
+        let res = g.execute("origin", &mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::UnknownModifier { .. })));
+        Ok(())
+    }
 
-        // It should have also produced a new rule foo with the value bar
-        let origin = String::from("foo");
-        assert_eq!("bar", grammar.execute(&origin, &mut rand::thread_rng())?);
+    #[test]
+    fn check_modifiers_in_actions() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#[hero:#name.possessive#]story#"],
+            "story" => vec!["#hero.capitalize#"],
+            "name" => vec!["Arjun"]
+        };
+        let mut g = Grammar::from_map(input)?;
+        assert!(matches!(
+            g.check_modifiers(),
+            Err(Error::UnknownModifier { key, modifier }) if key == "name" && modifier == "possessive"
+        ));
 
+        g.add_modifier("possessive", |s: &str| format!("{}'s", s));
+        g.check_modifiers()?;
+        g.remove_modifier("capitalize");
+        assert!(matches!(
+            g.check_modifiers(),
+            Err(Error::UnknownModifier { key, modifier }) if key == "hero" && modifier == "capitalize"
+        ));
         Ok(())
     }
 
     #[test]
-    fn execute_function() -> Result<()> {
+    fn recursion_limit() -> Result<()> {
         let input = hashmap! {
-            "origin" => vec!["#setFoo##baz#"],
-            "setFoo" => vec!["[foo:bar][bar:#[qux:quux]baz#]"],
-            "baz" => vec!["baz"]
+            "origin" => vec!["#origin#"]
         };
-        let mut grammar = Grammar::from_map(input)?;
+        let g = Grammar::from_map(input)?;
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(
+            res,
+            Err(Error::RecursionLimit(path))
+                if path.len() == DEFAULT_MAX_DEPTH + 1 && path.iter().all(|k| k == "origin")
+        ));
+        Ok(())
+    }
 
-        // The first invocation should produce the string baz from the rule baz
-        let origin = String::from("origin");
-        assert_eq!("baz", grammar.execute(&origin, &mut rand::thread_rng())?);
+    #[test]
+    fn recursion_limit_through_actions() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#[a:#b#]a#"],
+            "b" => vec!["#[c:#origin#]c#"]
+        };
+        let g = Grammar::from_map(input)?.with_max_depth(Some(5));
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(
+            res,
+            Err(Error::RecursionLimit(path)) if path == vec!["origin", "b", "origin", "b", "origin", "b"]
+        ));
+        Ok(())
+    }
 
-        // It should have also produced a new rule foo with the value bar
-        let origin = String::from("foo");
-        assert_eq!("bar", grammar.execute(&origin, &mut rand::thread_rng())?);
+    #[test]
+    fn recursion_limit_fits_on_spawned_thread() {
+        // Recursing through actions and modifiers uses the most stack per
+        // level. Threads spawned with the default stack size must not overflow
+        std::thread::spawn(|| -> Result<()> {
+            for rule in ["#[x:#origin#]a#", "#[x:#origin.capitalize#]a.s#"] {
+                let mut g = grammar! { "origin" => rule }?;
+                let res = g.flatten(&mut rand::thread_rng());
+                assert!(matches!(res, Err(Error::RecursionLimit(_))));
+                let res = g.execute_traced("origin", &mut rand::thread_rng());
+                assert!(matches!(res, Err(Error::RecursionLimit(_))));
+            }
+            Ok(())
+        })
+        .join()
+        .unwrap()
+        .unwrap();
+    }
 
-        // ..and a new rule bar with the value baz
-        let origin = String::from("bar");
-        assert_eq!("baz", grammar.execute(&origin, &mut rand::thread_rng())?);
+    #[test]
+    fn max_depth() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#a#"],
+            "a" => vec!["#b#"],
+            "b" => vec!["b"]
+        };
+        let mut g = Grammar::from_map(input)?.with_max_depth(Some(3));
+        assert_eq!("b", g.flatten(&mut rand::thread_rng())?);
 
-        // ..aaaand a new rule qux with the value quux
-        let origin = String::from("qux");
-        assert_eq!("quux", grammar.execute(&origin, &mut rand::thread_rng())?);
+        g.set_max_depth(Some(2));
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(
+            matches!(res, Err(Error::RecursionLimit(path)) if path == vec!["origin", "a", "b"])
+        );
 
+        g.set_max_depth(None);
+        assert_eq!("b", g.flatten(&mut rand::thread_rng())?);
         Ok(())
     }
 
+    fn exponential_grammar() -> Result<Grammar> {
+        let input = hashmap! {
+            "origin" => vec!["#a##a#"],
+            "a" => vec!["#b##b#"],
+            "b" => vec!["#c##c#"],
+            "c" => vec!["#d##d#"],
+            "d" => vec!["#[x:#e#][x:POP]e##e#"],
+            "e" => vec!["e"]
+        };
+        Grammar::from_map(input)
+    }
+
     #[test]
-    fn pop_rule() -> Result<()> {
+    fn within_limits() -> Result<()> {
+        let g = exponential_grammar()?.with_limits(Limits {
+            max_expansions: Some(79),
+            max_actions: Some(32),
+            max_pushes: Some(16),
+            max_output_bytes: Some(32),
+        });
+        assert_eq!("e".repeat(32), g.flatten(&mut rand::thread_rng())?);
+        Ok(())
+    }
+
+    #[test]
+    fn expansion_limit() -> Result<()> {
+        let g = exponential_grammar()?.with_limits(Limits {
+            max_expansions: Some(78),
+            ..Limits::default()
+        });
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::ExpansionLimit(78))));
+        Ok(())
+    }
+
+    #[test]
+    fn action_limit() -> Result<()> {
+        let g = exponential_grammar()?.with_limits(Limits {
+            max_actions: Some(31),
+            ..Limits::default()
+        });
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::ActionLimit(31))));
+        Ok(())
+    }
+
+    #[test]
+    fn push_limit() -> Result<()> {
+        let g = exponential_grammar()?.with_limits(Limits {
+            max_pushes: Some(15),
+            ..Limits::default()
+        });
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::PushLimit(15))));
+        Ok(())
+    }
+
+    #[test]
+    fn output_limit() -> Result<()> {
+        let g = exponential_grammar()?.with_limits(Limits {
+            max_output_bytes: Some(31),
+            ..Limits::default()
+        });
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::OutputLimit(31))));
+
+        // Modifiers which grow their input are limited as well
         let input = hashmap! {
-            "origin" => vec!["#[foo:baz]foo##[foo:POP]foo#"],
-            "foo" => vec!["bar"]
+            "origin" => vec!["#a.repeat#"],
+            "a" => vec!["a"]
         };
-        let mut grammar = Grammar::from_map(input)?;
-        assert_eq!(
-            "bazbar",
-            grammar.execute(&String::from("origin"), &mut rand::thread_rng())?
-        );
+        let g = Grammar::from_map(input)?
+            .with_modifier("repeat", |s: &str| s.repeat(1000))
+            .with_limits(Limits {
+                max_output_bytes: Some(999),
+                ..Limits::default()
+            });
+        let res = g.flatten(&mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::OutputLimit(999))));
+        Ok(())
+    }
+
+    #[test]
+    fn execute_into_matches_execute() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#[hero:#name#]story# #name.capitalize#",
+            "story" => ["#hero# met #name#.", "#hero# left."],
+            "name" => ["mia", "ada", "zoe"]
+        }?;
+        for seed in 0..20 {
+            let expected = g
+                .clone()
+                .execute("origin", &mut StdRng::seed_from_u64(seed))?;
+            let mut into = g.clone();
+            let mut output = String::from(">");
+            into.execute_into("origin", &mut output, &mut StdRng::seed_from_u64(seed))?;
+            assert_eq!(output, format!(">{}", expected));
+            assert_eq!(into.rule_stack("hero").len(), 1);
+
+            let mut bytes = Vec::new();
+            g.clone()
+                .execute_into_io("origin", &mut bytes, &mut StdRng::seed_from_u64(seed))?;
+            assert_eq!(String::from_utf8(bytes).unwrap(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn execute_into_stops_at_limit() -> Result<()> {
+        let mut g = grammar! {
+            "origin" => "#a##a##a#",
+            "a" => "abcd"
+        }?
+        .with_limits(Limits {
+            max_output_bytes: Some(10),
+            ..Limits::default()
+        });
+        let mut output = String::new();
+        let res = g.execute_into("origin", &mut output, &mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::OutputLimit(10))));
+        assert_eq!(output, "abcdabcd");
+        Ok(())
+    }
+
+    #[test]
+    fn execute_into_io_error() -> Result<()> {
+        struct Full;
+        impl io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::WriteZero.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut g = grammar! { "origin" => "[a:b]#a#" }?;
+        let res = g.execute_into_io("origin", &mut Full, &mut rand::thread_rng());
+        assert!(matches!(res, Err(Error::IoError(e)) if e.kind() == io::ErrorKind::WriteZero));
+        // Changes made before the failure are kept, as with execute
+        assert_eq!(g.rule_stack("a").len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn distribution_state_persists_across_executions() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#suit#"],
+            "suit" => vec!["hearts", "diamonds", "clubs", "spades"]
+        };
+        let mut g = Grammar::from_map(input)?.with_distribution("suit", Distribution::ShuffleDeck);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..5 {
+            let mut suits = (0..4)
+                .map(|_| g.execute("origin", &mut rng))
+                .collect::<Result<Vec<_>>>()?;
+            suits.sort();
+            assert_eq!(suits, vec!["clubs", "diamonds", "hearts", "spades"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn distribution_state_discarded_by_flatten() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#coin#"],
+            "coin" => vec!["heads", "tails"]
+        };
+        let mut g = Grammar::from_map(input)?.with_distribution("coin", Distribution::AvoidLast(1));
+        let mut rng = StdRng::seed_from_u64(0);
+        let first = g.execute("origin", &mut rng)?;
+        for _ in 0..20 {
+            // flatten never sees the other side of the coin as the last choice
+            let flattened = g.flatten(&mut rng)?;
+            assert_ne!(first, flattened);
+        }
+        assert_ne!(first, g.execute("origin", &mut rng)?);
+        assert_eq!(Distribution::AvoidLast(1), g.distribution("coin"));
+        assert_eq!(Distribution::Uniform, g.distribution("origin"));
+        Ok(())
+    }
+
+    #[test]
+    fn execute_traced() -> Result<()> {
+        use crate::{TraceAction, TraceNode};
+        let input = vec![
+            ("origin", vec![("#[hero:#name#]story#", 1)]),
+            ("story", vec![("#hero# met #[hero:POP]animal.a#", 1)]),
+            ("name", vec![("Arjun", 1)]),
+            ("animal", vec![("cat", 0), ("owl", 1)]),
+        ];
+        let mut g = Grammar::from_map(input)?;
+        let trace = g.execute_traced("origin", &mut rand::thread_rng())?;
+        assert_eq!(trace.output, "Arjun met an owl");
+
+        let leaf = |key: &str, choice, range| TraceNode {
+            key: key.to_string(),
+            choice,
+            missing: false,
+            actions: vec![],
+            modifiers: vec![],
+            range,
+            children: vec![],
+        };
+        let expected = TraceNode {
+            key: "origin".into(),
+            choice: 0,
+            missing: false,
+            actions: vec![],
+            modifiers: vec![],
+            range: 0..16,
+            children: vec![TraceNode {
+                key: "story".into(),
+                choice: 0,
+                missing: false,
+                actions: vec![TraceAction::Push {
+                    key: "hero".into(),
+                    values: vec!["Arjun".into()],
+                    expansions: vec![vec![leaf("name", 0, 0..5)]],
+                }],
+                modifiers: vec![],
+                range: 0..16,
+                children: vec![
+                    leaf("hero", 0, 0..5),
+                    TraceNode {
+                        actions: vec![TraceAction::Pop { key: "hero".into() }],
+                        modifiers: vec!["a".into()],
+                        ..leaf("animal", 1, 10..16)
+                    },
+                ],
+            }],
+        };
+        assert_eq!(trace.root, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn execute_traced_bare_actions() -> Result<()> {
+        use crate::TraceAction;
+        let input = hashmap! {
+            "origin" => vec!["[#setFoo#][bar:POP]"],
+            "setFoo" => vec!["[foo:x]"]
+        };
+        let mut g = Grammar::from_map(input)?;
+        let trace = g.execute_traced("origin", &mut rand::thread_rng())?;
+        assert_eq!(trace.output, "");
+        assert!(trace.root.children.is_empty());
+        assert!(matches!(
+            &trace.root.actions[..],
+            [
+                TraceAction::Unlabeled { output, expansions },
+                TraceAction::Pop { key }
+            ] if output.is_empty() && expansions[0].actions.len() == 1 && key == "bar"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn execute_traced_multi_value_push() -> Result<()> {
+        use crate::TraceAction;
+        let mut g = grammar! {
+            "origin" => "[pet:#animal#,a #animal.s# pack]#pet#",
+            "animal" => "wolf"
+        }?;
+        let trace = g.execute_traced("origin", &mut rand::thread_rng())?;
+        match &trace.root.actions[..] {
+            [TraceAction::Push {
+                key,
+                values,
+                expansions,
+            }] => {
+                assert_eq!(key, "pet");
+                assert_eq!(values, &vec!["wolf".to_string(), "a wolves pack".into()]);
+                assert_eq!(expansions[0][0].range, 0..4);
+                assert_eq!(expansions[1][0].range, 2..8);
+            }
+            actions => panic!("unexpected actions {:?}", actions),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn execute_traced_changing_modifiers() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["> #story.replace(cat,dog)# #story.capitalize#"],
+            "story" => vec!["a #animal#"],
+            "animal" => vec!["cat"]
+        };
+        let mut g = Grammar::from_map(input)?;
+        let trace = g.execute_traced("origin", &mut rand::thread_rng())?;
+        assert_eq!(trace.output, "> a dog A cat");
+
+        let replaced = &trace.root.children[0];
+        assert_eq!(replaced.modifiers, vec!["replace(cat,dog)"]);
+        assert_eq!(replaced.range, 2..7);
+        assert_eq!(replaced.children[0].range, 2..7);
+
+        // Capitalizing leaves the animal in place, but changes the story
+        let capitalized = &trace.root.children[1];
+        assert_eq!(capitalized.range, 8..13);
+        assert_eq!(capitalized.children[0].range, 8..13);
+        Ok(())
+    }
+
+    #[test]
+    fn replay() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#[hero:#name#]story#"],
+            "story" => vec!["#hero# met #animal.a#", "#hero# ran"],
+            "name" => vec!["Arjun", "Yuuma", "Darcy"],
+            "animal" => vec!["cat", "owl"]
+        };
+        let g = Grammar::from_map(input)?;
+        assert_eq!("Darcy met an owl", g.replay("origin", &[0, 2, 0, 0, 1])?);
+        assert_eq!("Yuuma ran", g.replay("origin", &[0, 1, 1, 0])?);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let trace = g.clone().execute_traced("origin", &mut rng)?;
+            assert_eq!(trace.output, g.replay("origin", &trace.choices)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replay_ignores_unrelated_keys() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#name# #animal#"],
+            "name" => vec!["Arjun", "Yuuma", "Darcy"],
+            "animal" => vec!["cat", "owl"]
+        };
+        let g = Grammar::from_map(input)?;
+        let trace = g
+            .clone()
+            .execute_traced("origin", &mut rand::thread_rng())?;
+
+        let input = hashmap! {
+            "origin" => vec!["#name# #animal#"],
+            "aardvark" => vec!["a", "b", "c"],
+            "name" => vec!["Arjun", "Yuuma", "Darcy"],
+            "animal" => vec!["cat", "owl"],
+            "zebra" => vec!["z"]
+        };
+        let g = Grammar::from_map(input)?;
+        assert_eq!(trace.output, g.replay("origin", &trace.choices)?);
+        Ok(())
+    }
+
+    #[test]
+    fn replay_errors() -> Result<()> {
+        let input = hashmap! {
+            "origin" => vec!["#name#"],
+            "name" => vec!["Arjun", "Yuuma", "Darcy"]
+        };
+        let g = Grammar::from_map(input)?;
+        assert!(matches!(
+            g.replay("origin", &[0, 3]),
+            Err(Error::InvalidChoice { key, choice: 3, rules: 3 }) if key == "name"
+        ));
+        assert!(matches!(
+            g.replay("origin", &[0]),
+            Err(Error::MissingChoice(key)) if key == "name"
+        ));
+        assert!(matches!(
+            g.replay("origin", &[0, 1, 2, 0]),
+            Err(Error::UnusedChoices(2))
+        ));
         Ok(())
     }
 
@@ -459,8 +2568,89 @@ mod tests {
         let origin = String::from("foo");
         assert!(matches!(
             grammar.execute(&origin, &mut rng),
+            Err(Error::MissingKeyError(MissingKey { popped: true, .. }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_key_policy() -> Result<()> {
+        let mut g = grammar! {
+            "origin" => "#[hero:Mia]story#",
+            "story" => "#hero.capitalize# met #villain.capitalize#. #[hero:POP]hero#"
+        }?;
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(matches!(
+            g.clone().execute("origin", &mut rng),
             Err(Error::MissingKeyError(_))
         ));
+
+        g.set_missing_key_policy(MissingKeyPolicy::Parenthesize);
+        let (output, warnings) = g.clone().execute_with_warnings("origin", &mut rng)?;
+        assert_eq!(output, "Mia met ((villain)). ((hero))");
+        let warnings: Vec<_> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "origin[0] -> story[0] -> villain",
+                "origin[0] -> story[0] -> hero (removed by POP)"
+            ]
+        );
+
+        g.set_missing_key_policy(MissingKeyPolicy::Empty);
+        assert_eq!(g.clone().execute("origin", &mut rng)?, "Mia met . ");
+
+        g.set_missing_key_policy(MissingKeyPolicy::fallback(|m| format!("<{}>", m.key)));
+        assert_eq!(
+            g.clone().execute("origin", &mut rng)?,
+            "Mia met <villain>. <hero>"
+        );
+        assert_eq!(g.count_outputs("origin")?, OutputCount::Finite(1u32.into()));
+        let outputs = g.enumerate("origin").collect::<Result<Vec<_>>>()?;
+        assert_eq!(outputs, vec!["Mia met <villain>. <hero>"]);
+
+        let trace = g.execute_traced("origin", &mut rng)?;
+        let villain = &trace.root.children[0].children[1];
+        assert!(villain.missing);
+        assert_eq!(villain.modifiers, vec!["capitalize"]);
+        assert_eq!(&trace.output[villain.range.clone()], "<villain>");
+        assert_eq!(trace.choices, vec![0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_key_path() -> Result<()> {
+        let g = grammar! {
+            "origin" => [ "a", "b", "#[hero:#name#]story#" ],
+            "story" => [ "#hero# and #heroPet#", "#hero#" ],
+            "name" => "Mia"
+        }?;
+        match g.replay("origin", &[2, 0, 0, 0]) {
+            Err(Error::MissingKeyError(missing)) => {
+                assert_eq!(missing.key, "heroPet");
+                assert_eq!(
+                    missing.path,
+                    vec![("origin".to_string(), 2), ("story".to_string(), 0)]
+                );
+                assert!(!missing.popped);
+                assert_eq!(missing.to_string(), "origin[2] -> story[0] -> heroPet");
+            }
+            other => panic!("expected a missing key error, got {:?}", other),
+        }
+
+        let g = grammar! {
+            "origin" => "#[hero:Mia]hero##[hero:POP]story#",
+            "story" => "#hero#"
+        }?;
+        match g.flatten(&mut rand::thread_rng()) {
+            Err(e @ Error::MissingKeyError(MissingKey { popped: true, .. })) => {
+                assert_eq!(
+                    e.to_string(),
+                    "Missing key: origin[0] -> story[0] -> hero (removed by POP)"
+                );
+            }
+            other => panic!("expected a popped key error, got {:?}", other),
+        }
         Ok(())
     }
 }
@@ -140,7 +140,7 @@
 //! # fn main() -> Result<()> {
 //! // This time, origin has a side-effect: it creates the rule 'aside'
 //! let mut g = grammar! {
-//!     "origin" => "#[aside:Rust is, too]tool# is #description#!",
+//!     "origin" => "#[aside:Rust is\\, too]tool# is #description#!",
 //!     "tool" => "tracery",
 //!     "description" => [ "fun", "awesome" ]
 //! }?;
@@ -168,9 +168,9 @@
 //! ### flatten
 //! [`flatten`], unlike [`execute`], always operates on the default rule of the
 //! Grammar ("origin" by default), but like [`execute`], takes an instance of
-//! [`rand::Rng`] to use during generation. In addition, [`flatten`] creates a
-//! clone of the Grammar to use during generation, then discards it, which means
-//! that any side-effects that occur will be discarded when it's done.
+//! [`rand::Rng`] to use during generation. In addition, [`flatten`] only takes
+//! a `&self` reference: any side-effects that occur are recorded separately
+//! from the Grammar, and discarded when it's done.
 //!
 //! ```
 //! use tracery::grammar;
@@ -192,6 +192,16 @@
 //! # }
 //! ```
 //!
+//! ### flatten_many
+//! With the optional `rayon` feature, [`flatten_many`] produces many outputs
+//! from the default rule in parallel, using one seed to make the results
+//! reproducible regardless of the number of threads.
+//!
+//! ### execute_into
+//! [`execute_into`] and [`execute_into_io`] work like [`execute`], but write
+//! the output straight to a [`std::fmt::Write`] or [`std::io::Write`] as it is
+//! produced, instead of returning a new String.
+//!
 //! # Language Concepts
 //! A *grammar* is a map from a set of string *key*s to a stack of *rulesets*,
 //! notionally rooted at an "origin" node, associated by default with the key
@@ -205,7 +215,9 @@
 //! A *ruleset* is a list (internally a `Vec<String>`) of strings, each
 //! representing a possible expansion of the associated key, to be chosen at
 //! random when expanding that key, containing one or more *plaintexts*, *tags*,
-//! or *action*s.
+//! or *action*s. Each rule in a ruleset can optionally be given a *weight*,
+//! which makes it proportionally more or less likely to be chosen than the
+//! other rules in the ruleset. See [`WeightedRule`] for details.
 //!
 //! An *action* is enclosed by square brackets (`[`, `]`) and can be either
 //! *labeled* or *unlabeled*.
@@ -216,55 +228,105 @@
 //! If `key` does not exist already, it will be created. A special exception is
 //! a pop action which takes the form `[key:POP]`, and will pop the top ruleset
 //! off the stack of rulesets associated with `key`. If the stack becomes empty,
-//! the key will be deleted from the associated grammar.
+//! the key will be deleted from the associated grammar. A labeled action can
+//! also be given several comma-separated rules, as in `[animal:cat,dog,owl]`,
+//! which pushes a ruleset containing the result of each one, so that later
+//! expansions of `key` choose among them. A literal comma can be written as
+//! `\,`.
 //!
 //! An *unlabeled action* is a single *tag* encased in square brackets such as
 //! `[#setPronouns#]` and is typically used to call a function-like ruleset
 //! which will use labeled actions to set values for some set of keys (like
 //! setting a character's pronouns for a story).
 //!
+//! Actions can appear anywhere in a rule, between plaintexts and tags, as in
+//! `hello [name:world]#name#[name:POP]`. They are executed in order as the
+//! rule is expanded, and add nothing to its output.
+//!
 //! A *tag* is a key, encased in hashes (`#`), which will be replaced in the
 //! output by a random rule chosen from the topmost ruleset of the key's
 //! associated ruleset stack. The key in a tag can also be preceded by one or
 //! more actions, which will be executed before the tag is expanded. Some
 //! examples of valid tags include: `#foo#`, `#[foo:#bar#]baz#`, and
-//! `#[#setPronouns#][#setJob#][#setPet#]hero#`.
+//! `#[#setPronouns#][#setJob#][#setPet#]hero#`. A tag can also consist of
+//! actions alone, as in `#[hero:#name#]#`, in which case it expands to nothing.
 //!
-//! A *plaintext* is any text in a rule which is not a tag or action.
+//! A tag's key can also be followed by one or more *modifiers*, each preceded
+//! by a `.`, which transform the expansion of the tag in order. For instance,
+//! `#animal.s.capitalize#` pluralizes, then capitalizes, the expansion of
+//! `animal`. A modifier can also be passed a comma-separated list of
+//! arguments in parentheses, as in `#animal.replace(a,e)#`. The built-in
+//! modifiers are `a`, `capitalize`, `capitalizeAll`, `comma`, `ed`,
+//! `inQuotes`, `replace`, and `s`. Custom modifiers can be registered with
+//! [`Grammar::add_modifier`] and [`Grammar::add_modifier_with_args`].
+//!
+//! A *plaintext* is any text in a rule which is not a tag or action. A `#`,
+//! `[`, `]` or `\` can be included in a plaintext by preceding it with a
+//! backslash, as in `\#hashtag`. In the values of a labeled action, `,` can
+//! also be escaped, and in keys, `:` and `.` can be too. Any other backslash
+//! is left as it is. A `[` which does not begin an action is plaintext too,
+//! but one followed by a key and `:` must begin a complete labeled action, so
+//! `[a:x` is an error rather than text.
 //!
 //! [tracery]: https://tracery.io/
 //! [Kate Compton]: http://www.galaxykate.com/
 //! [Language Concepts]: index.html#language-concepts
 //! [`grammar!`]: macro.grammar.html
 //! [`Grammar::from_map`]: struct.Grammar.html#method.from_map
+//! [`WeightedRule`]: struct.WeightedRule.html
+//! [`Grammar::add_modifier`]: struct.Grammar.html#method.add_modifier
+//! [`Grammar::add_modifier_with_args`]: struct.Grammar.html#method.add_modifier_with_args
 //! [`execute`]: struct.Grammar.html#method.execute
 //! [`flatten`]: struct.Grammar.html#method.flatten
+//! [`flatten_many`]: struct.Grammar.html#method.flatten_many
+//! [`execute_into`]: struct.Grammar.html#method.execute_into
+//! [`execute_into_io`]: struct.Grammar.html#method.execute_into_io
+//! [`std::fmt::Write`]: https://doc.rust-lang.org/std/fmt/trait.Write.html
+//! [`std::io::Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
 //! [`rand::Rng`]: http://docs.rs/rand/latest/rand/trait.Rng.html
 
+mod count;
+pub use crate::count::OutputCount;
+pub use num_bigint::BigUint;
+mod distribution;
+pub use crate::distribution::Distribution;
+mod enumerate;
+pub use crate::enumerate::Enumerate;
 mod error;
-pub use crate::error::Error;
+pub use crate::error::{Error, MissingKey, ParseError};
 mod execute;
-pub(crate) use crate::execute::Execute;
+pub(crate) use crate::execute::{Execute, Output, State};
 mod grammar;
 pub use crate::grammar::Grammar;
+mod limits;
+pub use crate::limits::Limits;
+mod missing_key;
+pub use crate::missing_key::MissingKeyPolicy;
 mod modifiers;
 mod node;
 use crate::node::Node;
+mod overlay;
+use crate::overlay::Overlay;
 mod parser;
 mod rule;
 use crate::rule::Rule;
+pub use crate::rule::WeightedRule;
 mod tag;
+mod trace;
+pub use crate::trace::{Trace, TraceAction, TraceNode};
+mod validate;
+pub use crate::validate::Diagnostic;
 
 #[doc(hidden)]
 #[macro_export]
 macro_rules! grammar_item {
     ($map:ident, ) => {};
-    ($map:ident, $key:literal => [$($value: literal),+ $(,)?] $(, $($rest: tt)*)?) => {
-        $map.insert($key, vec!($($value,)+));
+    ($map:ident, $key:literal => [$($value: expr),+ $(,)?] $(, $($rest: tt)*)?) => {
+        $map.insert($key, vec!($($crate::WeightedRule::from($value),)+));
         $($crate::grammar_item!($map, $($rest)*))?
     };
-    ($map:ident, $key:literal => $value: literal $(, $($rest: tt)*)?) => {
-        $map.insert($key, vec!($value));
+    ($map:ident, $key:literal => $value: expr $(, $($rest: tt)*)?) => {
+        $map.insert($key, vec!($crate::WeightedRule::from($value)));
         $($crate::grammar_item!($map, $($rest)*))?
     };
 }
@@ -275,10 +337,10 @@ macro_rules! grammar_count {
     ([$($ctr: tt)*] $(,)?) => {
         <[()]>::len(&[$($ctr)*])
     };
-    ([$($ctr: tt)*], $key: literal => [$($value: literal),+ $(,)?] $(, $($rest: tt)*)?) => {
+    ([$($ctr: tt)*], $key: literal => [$($value: expr),+ $(,)?] $(, $($rest: tt)*)?) => {
         $crate::grammar_count!([(), $($ctr)*] $(, $($rest)*)?)
     };
-    ([$($ctr: tt)*], $key: literal => $value: literal $(, $($rest: tt)*)?) => {
+    ([$($ctr: tt)*], $key: literal => $value: expr $(, $($rest: tt)*)?) => {
         $crate::grammar_count!([(), $($ctr)*] $(, $($rest)*)?)
     };
 }
@@ -289,6 +351,10 @@ macro_rules! grammar_count {
 /// case of a key having only one rule, `"key" => "rule"`. Equivalent to
 /// manually building a map and then calling [`Grammar::from_map`]
 ///
+/// Any rule can be given a weight by writing it as a `("rule", weight)` tuple,
+/// as in `"key" => [ ("common", 10), "rare" ]`. Rules without an explicit
+/// weight have a weight of 1. See [`WeightedRule`] for details.
+///
 /// # Returns
 /// Result<[`Grammar`], [`Error`]>
 ///
@@ -316,10 +382,30 @@ macro_rules! grammar_count {
 /// # }
 /// ```
 ///
+/// With weighted rules:
+///
+/// ```
+/// # use tracery::{grammar, Result};
+/// # fn main() -> Result<()> {
+/// let g = grammar! {
+///     "origin" => "#tool# is #description#!",
+///     "tool" => "tracery",
+///     "description" => [ ("fun", 1), ("awesome", 10) ]
+/// }?;
+/// # let output = g.flatten(&mut rand::thread_rng())?;
+/// # assert!(match output.as_str() {
+/// #     "tracery is fun!" | "tracery is awesome!" => true,
+/// #     _ => false,
+/// # });
+/// # Ok(())
+/// # }
+/// ```
+///
 /// [`Error`]: enum.Error.html
 /// [`Grammar`]: struct.Grammar.html
 /// [`Grammar::from_map`]: struct.Grammar.html#method.from_map
 /// [`Result`]: type.Result.html
+/// [`WeightedRule`]: struct.WeightedRule.html
 #[macro_export]
 macro_rules! grammar {
     ($($input: tt)+) => {
@@ -358,9 +444,7 @@ macro_rules! grammar {
 /// [`Grammar`]: struct.Grammar.html
 #[cfg(feature = "tracery_json")]
 pub fn from_json<S: AsRef<str>>(s: S) -> Result<Grammar> {
-    use std::collections::HashMap;
-    let map: HashMap<String, Vec<String>> = serde_json::from_str(s.as_ref())?;
-    Grammar::from_map(map)
+    Grammar::from_json(s)
 }
 
 /// Creates a new grammar from an input map
@@ -392,7 +476,7 @@ where
     I: IntoIterator<Item = (K, C)>,
     K: Into<String>,
     C: IntoIterator<Item = S>,
-    S: Into<String>,
+    S: Into<WeightedRule>,
 {
     Grammar::from_map(iter)
 }
@@ -459,7 +543,7 @@ where
     I: IntoIterator<Item = (K, C)>,
     K: Into<String>,
     C: IntoIterator<Item = S>,
-    S: Into<String>,
+    S: Into<WeightedRule>,
 {
     from_map(iter)?.execute(&crate::grammar::ORIGIN, &mut rand::thread_rng())
 }
@@ -474,8 +558,7 @@ mod tests {
     #[cfg(feature = "tracery_json")]
     use super::from_json;
     use super::from_map;
-    use super::grammar;
-    use super::Result;
+    use super::{flatten_map, Result};
     use maplit::hashmap;
 
     #[test]
@@ -489,6 +572,19 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_macro_weighted() -> Result<()> {
+        let g = grammar! {
+            "origin" => ("#foo#", 3),
+            "foo" => [("a", 0), "b", ("c", 0),],
+            "bar" => [String::from("a")]
+        }?;
+        for _ in 0..100 {
+            assert_eq!(g.flatten(&mut rand::thread_rng())?, "b");
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_flatten_map() {
         let source = hashmap! {
@@ -512,6 +608,24 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_bare_actions_in_text() -> Result<()> {
+        let g = grammar! {
+            "origin" => "hello [name:world]#name#[name:POP], [name:friend]#name#!",
+            "name" => "you"
+        }?;
+        assert_eq!(g.flatten(&mut rand::thread_rng())?, "hello world, friend!");
+        Ok(())
+    }
+
+    #[test]
+    fn test_grammar_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<crate::Grammar>();
+        assert_send_sync::<crate::MissingKeyPolicy>();
+        assert_send_sync::<crate::Enumerate>();
+    }
+
     #[test]
     fn test_malformed_input() {
         let input = hashmap! { "a" => vec!["#a"]};
@@ -519,6 +633,23 @@ mod tests {
         assert!(matches!(res, Err(crate::Error::ParseError(_))));
     }
 
+    #[test]
+    fn test_malformed_input_location() {
+        let input = hashmap! { "a" => vec!["fine", "also fine", "#a.b."] };
+        match from_map(input) {
+            Err(crate::Error::ParseError(e)) => {
+                assert_eq!(e.key.as_deref(), Some("a"));
+                assert_eq!(e.index, Some(2));
+                assert_eq!((e.offset, e.line, e.column), (5, 1, 6));
+                assert_eq!(
+                    e.to_string(),
+                    "key 'a', rule 2, line 1, column 6: expected modifier_name"
+                );
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
     #[test]
     #[cfg(feature = "tracery_json")]
     fn test_flatten_json() {
@@ -548,4 +679,151 @@ mod tests {
         let res = from_json(input);
         assert!(matches!(res, Err(crate::Error::JsonError(_))));
     }
+
+    #[test]
+    fn test_empty_ruleset() {
+        let empty: Vec<&str> = vec![];
+        let input = hashmap! { "origin" => vec!["#a#"], "a" => empty };
+        let res = flatten_map(input);
+        assert!(matches!(res, Err(crate::Error::EmptyRuleset(key)) if key == "a"));
+    }
+
+    #[cfg(feature = "tracery_json")]
+    #[test]
+    fn test_empty_ruleset_json() {
+        let res = crate::flatten_json(r##"{ "origin": [ "#a#" ], "a": [] }"##);
+        assert!(matches!(res, Err(crate::Error::EmptyRuleset(key)) if key == "a"));
+    }
+
+    /// Generates a random grammar, built from fragments of tracery syntax
+    fn random_grammar<R: rand::Rng>(rng: &mut R) -> Vec<(String, Vec<(String, u32)>)> {
+        use rand::seq::SliceRandom;
+        const KEYS: &[&str] = &["origin", "a", "b", "c", "a.b", ""];
+        const FRAGMENTS: &[&str] = &[
+            "#",
+            "[",
+            "]",
+            ":",
+            ".",
+            ",",
+            "(",
+            ")",
+            "\\",
+            " ",
+            "a",
+            "b",
+            "é",
+            "POP",
+            "#a#",
+            "#b.s#",
+            "#c.a.capitalize#",
+            "#origin#",
+            "[a:b]",
+            "[b:POP]",
+            "[a:#b#]",
+            "#[c:#a#]b#",
+            "#[a:POP]#",
+            "#a.replace(a,é)#",
+            "#b.unknown#",
+            "#[#a#]c#",
+            "[c:POP]",
+            "#[a:b,c]#",
+            "[b:#a#,x\\,y,]",
+            "#[a:#[b:c]#]c#",
+            "[a:#[b:#c#]#]",
+            "#[#[b:c]#]a#",
+            "[#[c:a,b]#]",
+        ];
+        let keys = rng.gen_range(1..=KEYS.len());
+        KEYS[..keys]
+            .iter()
+            .map(|&key| {
+                let rules = (0..rng.gen_range(0..4))
+                    .map(|_| {
+                        let rule: String = (0..rng.gen_range(0..8))
+                            .map(|_| *FRAGMENTS.choose(rng).unwrap())
+                            .collect();
+                        (rule, rng.gen_range(0..3))
+                    })
+                    .collect();
+                (key.to_string(), rules)
+            })
+            .collect()
+    }
+
+    /// Runs a grammar in every supported way, ignoring errors
+    fn exercise(g: crate::Grammar, rng: &mut rand::rngs::StdRng) {
+        use crate::{Distribution, Limits, MissingKeyPolicy};
+        use rand::Rng;
+
+        let _ = g.validate();
+        let _ = g.check_modifiers();
+        let _ = g.count_outputs("origin");
+        let _ = g.enumerate("origin").take(20).count();
+        let limits = Limits {
+            max_expansions: Some(200),
+            max_output_bytes: Some(4096),
+            ..Limits::default()
+        };
+        let g = g.with_limits(limits);
+        let _ = g.flatten(rng);
+        let _ = g.clone().execute_traced("a", rng);
+        let choices: Vec<usize> = (0..rng.gen_range(0..6))
+            .map(|_| rng.gen_range(0..4))
+            .collect();
+        let _ = g.replay("origin", &choices);
+        let mut g = g
+            .with_strict_modifiers(rng.gen())
+            .with_uniform_derivations(rng.gen())
+            .with_distribution("a", Distribution::ShuffleDeck)
+            .with_distribution("b", Distribution::AvoidLast(2))
+            .with_distribution("c", Distribution::Falloff(0.5))
+            .with_missing_key_policy(MissingKeyPolicy::Parenthesize);
+        for _ in 0..3 {
+            let _ = g.execute_with_warnings("origin", rng);
+        }
+    }
+
+    #[test]
+    fn test_random_grammars_do_not_panic() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(17);
+        for _ in 0..2000 {
+            let map = random_grammar(&mut rng);
+            if let Ok(g) = crate::Grammar::from_map(map.clone()) {
+                let unparsed = g.to_map();
+                let reparsed = crate::Grammar::from_map(unparsed.clone()).unwrap();
+                assert_eq!(reparsed.to_map(), unparsed, "{:?}", map);
+                exercise(g, &mut rng);
+            }
+            let _ = crate::Grammar::from_map_collect_errors(map);
+        }
+    }
+
+    #[cfg(feature = "tracery_json")]
+    #[test]
+    fn test_random_json_grammars_do_not_panic() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(18);
+        for _ in 0..500 {
+            let map: serde_json::Map<String, serde_json::Value> = random_grammar(&mut rng)
+                .into_iter()
+                .map(|(key, rules)| {
+                    let rules = rules
+                        .into_iter()
+                        .map(|(rule, weight)| match weight {
+                            1 => serde_json::json!(rule),
+                            _ => serde_json::json!({ "rule": rule, "weight": weight }),
+                        })
+                        .collect();
+                    (key, serde_json::Value::Array(rules))
+                })
+                .collect();
+            let json = serde_json::Value::Object(map).to_string();
+            if let Ok(g) = crate::Grammar::from_json(&json) {
+                exercise(g, &mut rng);
+            }
+            let _ = crate::Grammar::from_json_collect_errors(&json);
+        }
+    }
 }
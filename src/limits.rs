@@ -0,0 +1,57 @@
+/// Limits on the amount of work a single execution of a [`Grammar`] may do.
+///
+/// Each limit is optional, and a limit of `None` means that no limit is
+/// enforced. Exceeding a limit causes the execution to fail with the
+/// corresponding [`Error`] variant. Limits are useful when executing
+/// grammars from untrusted sources, which may otherwise produce
+/// exponentially large outputs.
+///
+/// # Examples
+/// ```
+/// use tracery::{grammar, Error, Limits};
+/// # use tracery::Result;
+/// # fn main() -> Result<()> {
+/// let g = grammar! {
+///     "origin" => "#a##a#",
+///     "a" => "#b##b#",
+///     "b" => "#c##c#",
+///     "c" => "ha"
+/// }?.with_limits(Limits {
+///     max_output_bytes: Some(8),
+///     ..Limits::default()
+/// });
+/// let res = g.flatten(&mut rand::thread_rng());
+/// assert!(matches!(res, Err(Error::OutputLimit(8))));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Error`]: enum.Error.html
+/// [`Grammar`]: struct.Grammar.html
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of keys expanded, including the key being executed.
+    /// Exceeding it produces [`Error::ExpansionLimit`]
+    ///
+    /// [`Error::ExpansionLimit`]: enum.Error.html#variant.ExpansionLimit
+    pub max_expansions: Option<usize>,
+
+    /// The maximum number of actions executed, including pop actions.
+    /// Exceeding it produces [`Error::ActionLimit`]
+    ///
+    /// [`Error::ActionLimit`]: enum.Error.html#variant.ActionLimit
+    pub max_actions: Option<usize>,
+
+    /// The maximum number of rules pushed onto rule stacks by labeled actions.
+    /// Exceeding it produces [`Error::PushLimit`]
+    ///
+    /// [`Error::PushLimit`]: enum.Error.html#variant.PushLimit
+    pub max_pushes: Option<usize>,
+
+    /// The maximum length, in bytes, of the output and of any intermediate
+    /// expansion produced along the way. Exceeding it produces
+    /// [`Error::OutputLimit`]
+    ///
+    /// [`Error::OutputLimit`]: enum.Error.html#variant.OutputLimit
+    pub max_output_bytes: Option<usize>,
+}
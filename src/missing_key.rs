@@ -0,0 +1,91 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{Error, MissingKey, Result};
+
+/// A function which renders a reference to a missing key
+pub(crate) type FallbackFn = Arc<dyn Fn(&MissingKey) -> String + Send + Sync>;
+
+/// How a [`Grammar`] handles a reference to a key which does not exist.
+///
+/// With any policy other than [`Error`], the missing key is rendered in place
+/// and the expansion continues. Each missing key is recorded as a warning,
+/// which can be retrieved with [`Grammar::execute_with_warnings`].
+///
+/// # Examples
+/// ```
+/// use tracery::{grammar, MissingKeyPolicy};
+/// # use tracery::Result;
+/// # fn main() -> Result<()> {
+/// let mut g = grammar! {
+///     "origin" => "#hero# met #villain#"
+/// }?.with_missing_key_policy(MissingKeyPolicy::Parenthesize);
+/// let (output, warnings) = g.execute_with_warnings("origin", &mut rand::thread_rng())?;
+/// assert_eq!(output, "((hero)) met ((villain))");
+/// assert_eq!(warnings.len(), 2);
+/// assert_eq!(warnings[1].key, "villain");
+///
+/// g.set_missing_key_policy(MissingKeyPolicy::fallback(|missing| missing.key.to_uppercase()));
+/// assert_eq!(g.flatten(&mut rand::thread_rng())?, "HERO met VILLAIN");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Grammar`]: struct.Grammar.html
+/// [`Error`]: enum.MissingKeyPolicy.html#variant.Error
+/// [`Grammar::execute_with_warnings`]: struct.Grammar.html#method.execute_with_warnings
+#[derive(Clone)]
+pub enum MissingKeyPolicy {
+    /// Fail with [`Error::MissingKeyError`]. This is the default
+    ///
+    /// [`Error::MissingKeyError`]: enum.Error.html#variant.MissingKeyError
+    Error,
+    /// Render the key in double parentheses, e.g. `((key))`, as tracery.js
+    /// does
+    Parenthesize,
+    /// Render nothing
+    Empty,
+    /// Render the result of calling the given function. See
+    /// [`MissingKeyPolicy::fallback`]
+    ///
+    /// [`MissingKeyPolicy::fallback`]: enum.MissingKeyPolicy.html#method.fallback
+    Fallback(FallbackFn),
+}
+
+impl Default for MissingKeyPolicy {
+    fn default() -> Self {
+        MissingKeyPolicy::Error
+    }
+}
+
+impl MissingKeyPolicy {
+    /// Creates a policy which renders each missing key with the given function
+    pub fn fallback<F>(f: F) -> MissingKeyPolicy
+    where
+        F: Fn(&MissingKey) -> String + Send + Sync + 'static,
+    {
+        MissingKeyPolicy::Fallback(Arc::new(f))
+    }
+
+    /// Renders a missing key according to this policy, or fails if the policy
+    /// is to fail
+    pub(crate) fn render(&self, missing: MissingKey) -> Result<String> {
+        match self {
+            MissingKeyPolicy::Error => Err(Error::MissingKeyError(missing)),
+            MissingKeyPolicy::Parenthesize => Ok(format!("(({}))", missing.key)),
+            MissingKeyPolicy::Empty => Ok(String::new()),
+            MissingKeyPolicy::Fallback(f) => Ok(f(&missing)),
+        }
+    }
+}
+
+impl fmt::Debug for MissingKeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingKeyPolicy::Error => write!(f, "Error"),
+            MissingKeyPolicy::Parenthesize => write!(f, "Parenthesize"),
+            MissingKeyPolicy::Empty => write!(f, "Empty"),
+            MissingKeyPolicy::Fallback(_) => write!(f, "Fallback(..)"),
+        }
+    }
+}
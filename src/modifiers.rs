@@ -1,57 +1,55 @@
 use inflector::string::pluralize;
 
 use std::collections::BTreeMap;
-use std::rc::Rc;
+use std::sync::Arc;
+
+/// A modifier function, which transforms the expansion of a tag given the
+/// arguments supplied to the modifier
+pub(crate) type ModifierFn = Arc<dyn Fn(&str, &[&str]) -> String + Send + Sync>;
+
+/// Wraps a modifier which takes no arguments as a [`ModifierFn`], ignoring any
+/// arguments supplied to it
+pub(crate) fn without_args<F>(f: F) -> ModifierFn
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    Arc::new(move |s: &str, _: &[&str]| f(s))
+}
 
-pub(crate) fn get_default_modifiers() -> BTreeMap<String, Rc<dyn Fn(&str) -> String>> {
-    let mut modifiers = BTreeMap::new();
+pub(crate) fn get_default_modifiers() -> BTreeMap<String, ModifierFn> {
+    let mut modifiers: BTreeMap<String, ModifierFn> = BTreeMap::new();
     let capitalize = |s: &str| {
         let mut iter = s.chars();
         let u = iter.next().map(|c| c.to_uppercase().to_string());
-        format!(
-            "{}{}",
-            u.unwrap_or_else(String::default),
-            iter.collect::<String>()
-        )
+        format!("{}{}", u.unwrap_or_default(), iter.collect::<String>())
     };
-    modifiers.insert(
-        "capitalize".into(),
-        Rc::new(capitalize) as Rc<dyn Fn(&str) -> String>,
-    );
+    modifiers.insert("capitalize".into(), without_args(capitalize));
     modifiers.insert(
         "capitalizeAll".into(),
-        Rc::new(move |s: &str| {
+        without_args(move |s: &str| {
             use split_preserve::SplitPreserveWS;
             SplitPreserveWS::new(s).map_words(capitalize).collect()
-        }) as Rc<dyn Fn(&str) -> String>,
+        }),
     );
     modifiers.insert(
         "inQuotes".into(),
-        Rc::new(|s: &str| format!("\"{}\"", s)) as Rc<dyn Fn(&str) -> String>,
+        without_args(|s: &str| format!("\"{}\"", s)),
     );
     modifiers.insert(
         "comma".into(),
-        Rc::new(|s: &str| {
+        without_args(|s: &str| {
             if s.ends_with(',') || s.ends_with('.') || s.ends_with('!') || s.ends_with('?') {
                 s.to_string()
             } else {
                 format!("{},", s)
             }
-        }) as Rc<dyn Fn(&str) -> String>,
+        }),
     );
-    modifiers.insert(
-        "s".into(),
-        Rc::new(|s: &str| pluralize::to_plural(s)) as Rc<dyn Fn(&str) -> String>,
-    );
-    let is_vowel = |c: char| -> bool {
-        match c {
-            'a' | 'e' | 'i' | 'o' | 'u' => true,
-            _ => false,
-        }
-    };
+    modifiers.insert("s".into(), without_args(|s: &str| pluralize::to_plural(s)));
+    let is_vowel = |c: char| -> bool { matches!(c, 'a' | 'e' | 'i' | 'o' | 'u') };
     modifiers.insert(
         "a".into(),
-        Rc::new(move |s: &str| {
+        without_args(move |s: &str| {
             format!(
                 "{} {}",
                 match s.chars().next().map(is_vowel) {
@@ -60,7 +58,7 @@ pub(crate) fn get_default_modifiers() -> BTreeMap<String, Rc<dyn Fn(&str) -> Str
                 },
                 s
             )
-        }) as Rc<dyn Fn(&str) -> String>,
+        }),
     );
 
     // Gets a char offset -n from the end. Returns None if n is larger than
@@ -74,7 +72,7 @@ pub(crate) fn get_default_modifiers() -> BTreeMap<String, Rc<dyn Fn(&str) -> Str
     };
     modifiers.insert(
         "ed".into(),
-        Rc::new(move |s: &str| {
+        without_args(move |s: &str| {
             use split_preserve::{SplitPreserveWS, Token};
             // Split, preserving whitespace
             let mut iter = SplitPreserveWS::new(s);
@@ -102,7 +100,7 @@ pub(crate) fn get_default_modifiers() -> BTreeMap<String, Rc<dyn Fn(&str) -> Str
                     Some('e') => format!("{}{}", s, "d"),
                     Some(_) | None => format!("{}{}", s, "ed"),
                 })
-                .unwrap_or_else(String::default);
+                .unwrap_or_default();
 
             // Collect the rest as a string
             let rest: String = iter
@@ -114,50 +112,59 @@ pub(crate) fn get_default_modifiers() -> BTreeMap<String, Rc<dyn Fn(&str) -> Str
 
             // Stitch prefix, first, and rest together into one String
             format!("{}{}{}", prefix, first, rest,)
-        }) as Rc<dyn Fn(&str) -> String>,
+        }),
+    );
+    modifiers.insert(
+        "replace".into(),
+        Arc::new(|s: &str, args: &[&str]| match args {
+            ["", ..] | [] => s.to_string(),
+            [from] => s.replace(from, ""),
+            [from, to, ..] => s.replace(from, to),
+        }),
     );
     modifiers
 }
 
+#[cfg(test)]
 mod tests {
     #[test]
     fn capitalize() {
         let mods = super::get_default_modifiers();
         let c = &mods["capitalize"];
-        assert_eq!(c(""), "");
-        assert_eq!(c("a"), "A");
-        assert_eq!(c("abc"), "Abc");
-        assert_eq!(c("a b"), "A b");
-        assert_eq!(c("aBC"), "ABC");
-        assert_eq!(c("ABC"), "ABC");
+        assert_eq!(c("", &[]), "");
+        assert_eq!(c("a", &[]), "A");
+        assert_eq!(c("abc", &[]), "Abc");
+        assert_eq!(c("a b", &[]), "A b");
+        assert_eq!(c("aBC", &[]), "ABC");
+        assert_eq!(c("ABC", &[]), "ABC");
 
         // Test expansion into multiple characters
-        assert_eq!(c("ß"), "SS");
-        assert_eq!(c("ßBC"), "SSBC");
-        assert_eq!(c("ßbc"), "SSbc");
-        assert_eq!(c("ß bc"), "SS bc");
+        assert_eq!(c("ß", &[]), "SS");
+        assert_eq!(c("ßBC", &[]), "SSBC");
+        assert_eq!(c("ßbc", &[]), "SSbc");
+        assert_eq!(c("ß bc", &[]), "SS bc");
     }
 
     #[test]
     fn capitalize_all() {
         let mods = super::get_default_modifiers();
         let c = &mods["capitalizeAll"];
-        assert_eq!(c(""), "");
-        assert_eq!(c("a"), "A");
-        assert_eq!(c("a b"), "A B");
-        assert_eq!(c("ABC"), "ABC");
-        assert_eq!(c("abc\nDEF"), "Abc\nDEF");
-        assert_eq!(c("ß bc"), "SS Bc");
-        assert_eq!(c("bc\t\nßßß"), "Bc\t\nSSßß");
-        assert_eq!(c("\ta\nb"), "\tA\nB");
+        assert_eq!(c("", &[]), "");
+        assert_eq!(c("a", &[]), "A");
+        assert_eq!(c("a b", &[]), "A B");
+        assert_eq!(c("ABC", &[]), "ABC");
+        assert_eq!(c("abc\nDEF", &[]), "Abc\nDEF");
+        assert_eq!(c("ß bc", &[]), "SS Bc");
+        assert_eq!(c("bc\t\nßßß", &[]), "Bc\t\nSSßß");
+        assert_eq!(c("\ta\nb", &[]), "\tA\nB");
     }
 
     #[test]
     fn in_quotes() {
         let mods = super::get_default_modifiers();
         let c = &mods["inQuotes"];
-        assert_eq!(c(""), r#""""#);
-        assert_eq!(c("hail eris"), r#""hail eris""#);
+        assert_eq!(c("", &[]), r#""""#);
+        assert_eq!(c("hail eris", &[]), r#""hail eris""#);
     }
 
     #[test]
@@ -165,13 +172,13 @@ mod tests {
         let mods = super::get_default_modifiers();
         let c = &mods["comma"];
 
-        assert_eq!(c("a,"), "a,");
-        assert_eq!(c("a."), "a.");
-        assert_eq!(c("a!"), "a!");
-        assert_eq!(c("a?"), "a?");
+        assert_eq!(c("a,", &[]), "a,");
+        assert_eq!(c("a.", &[]), "a.");
+        assert_eq!(c("a!", &[]), "a!");
+        assert_eq!(c("a?", &[]), "a?");
 
-        assert_eq!(c("a"), "a,");
-        assert_eq!(c(""), ",");
+        assert_eq!(c("a", &[]), "a,");
+        assert_eq!(c("", &[]), ",");
     }
 
     #[test]
@@ -179,13 +186,13 @@ mod tests {
         let mods = super::get_default_modifiers();
         let c = &mods["s"];
 
-        assert_eq!(c(""), "s");
-        assert_eq!(c("harpy"), "harpies");
-        assert_eq!(c("box"), "boxes");
-        assert_eq!(c("index"), "indices");
-        assert_eq!(c("goose"), "geese");
-        assert_eq!(c("ox"), "oxen");
-        assert_eq!(c("cat"), "cats");
+        assert_eq!(c("", &[]), "s");
+        assert_eq!(c("harpy", &[]), "harpies");
+        assert_eq!(c("box", &[]), "boxes");
+        assert_eq!(c("index", &[]), "indices");
+        assert_eq!(c("goose", &[]), "geese");
+        assert_eq!(c("ox", &[]), "oxen");
+        assert_eq!(c("cat", &[]), "cats");
     }
 
     #[test]
@@ -193,14 +200,27 @@ mod tests {
         let mods = super::get_default_modifiers();
         let c = &mods["a"];
 
-        assert_eq!(c(""), "a ");
-        assert_eq!(c("cat"), "a cat");
-        assert_eq!(c("a"), "an a");
-        assert_eq!(c("e"), "an e");
-        assert_eq!(c("i"), "an i");
-        assert_eq!(c("o"), "an o");
-        assert_eq!(c("u"), "an u");
-        assert_eq!(c("xylophone"), "a xylophone");
+        assert_eq!(c("", &[]), "a ");
+        assert_eq!(c("cat", &[]), "a cat");
+        assert_eq!(c("a", &[]), "an a");
+        assert_eq!(c("e", &[]), "an e");
+        assert_eq!(c("i", &[]), "an i");
+        assert_eq!(c("o", &[]), "an o");
+        assert_eq!(c("u", &[]), "an u");
+        assert_eq!(c("xylophone", &[]), "a xylophone");
+    }
+
+    #[test]
+    fn replace() {
+        let mods = super::get_default_modifiers();
+        let c = &mods["replace"];
+
+        assert_eq!(c("banana", &["a", "e"]), "benene");
+        assert_eq!(c("banana", &["an", ""]), "ba");
+        assert_eq!(c("banana", &["an"]), "ba");
+        assert_eq!(c("banana", &["", "x"]), "banana");
+        assert_eq!(c("banana", &[]), "banana");
+        assert_eq!(c("", &["a", "e"]), "");
     }
 
     #[test]
@@ -208,17 +228,17 @@ mod tests {
         let mods = super::get_default_modifiers();
         let c = &mods["ed"];
 
-        assert_eq!(c(""), "");
-        assert_eq!(c("box"), "boxed");
-        assert_eq!(c("hail eris"), "hailed eris");
-        assert_eq!(c("hail\t\neris"), "hailed\t\neris");
-        assert_eq!(c("\t\nhail eris"), "\t\nhailed eris");
+        assert_eq!(c("", &[]), "");
+        assert_eq!(c("box", &[]), "boxed");
+        assert_eq!(c("hail eris", &[]), "hailed eris");
+        assert_eq!(c("hail\t\neris", &[]), "hailed\t\neris");
+        assert_eq!(c("\t\nhail eris", &[]), "\t\nhailed eris");
 
-        assert_eq!(c("storey"), "storeyed");
-        assert_eq!(c("story"), "storied");
+        assert_eq!(c("storey", &[]), "storeyed");
+        assert_eq!(c("story", &[]), "storied");
 
-        assert_eq!(c("blame"), "blamed");
+        assert_eq!(c("blame", &[]), "blamed");
 
-        assert_eq!(c("\t"), "\t");
+        assert_eq!(c("\t", &[]), "\t");
     }
 }
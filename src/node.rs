@@ -1,7 +1,10 @@
+use crate::parser::escape_text;
 use crate::tag::Tag;
 use crate::Execute;
 use crate::Grammar;
+use crate::Output;
 use crate::Result;
+use crate::State;
 
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) enum Node {
@@ -32,11 +35,26 @@ impl From<String> for Node {
     }
 }
 
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Node::Tag(tag) => write!(f, "{}", tag),
+            Node::Text(s) => write!(f, "{}", escape_text(s)),
+        }
+    }
+}
+
 impl Execute for Node {
-    fn execute<R: ?Sized + rand::Rng>(&self, grammar: &mut Grammar, rng: &mut R) -> Result<String> {
+    fn execute<R: ?Sized + rand::Rng>(
+        &self,
+        grammar: &Grammar,
+        state: &mut State,
+        out: &mut Output<'_>,
+        rng: &mut R,
+    ) -> Result<()> {
         match self {
-            Node::Tag(ref tag) => tag.execute(grammar, rng),
-            Node::Text(ref s) => Ok(s.to_owned()),
+            Node::Tag(ref tag) => tag.execute(grammar, state, out, rng),
+            Node::Text(ref s) => out.write(s),
         }
     }
 }
@@ -0,0 +1,242 @@
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::{distribution::SelectionState, Grammar, Node, Rule};
+
+/// A key's rule stack, as changed by the actions run during an execution
+#[derive(Debug, Clone, Default)]
+struct Stack {
+    /// The number of rulesets at the bottom of the grammar's rule stack which
+    /// have not been popped
+    base: usize,
+    /// The rulesets pushed on top of those, topmost last
+    pushed: Vec<Arc<[Rule]>>,
+    /// Whether the key was removed by popping its last ruleset
+    popped: bool,
+}
+
+impl Stack {
+    fn depth(&self) -> usize {
+        self.base + self.pushed.len()
+    }
+}
+
+/// The changes made to a grammar's rule stacks during an execution, recorded
+/// on top of the unchanged grammar. Only the keys which are pushed or popped
+/// are stored, so an execution costs nothing for keys which actions don't touch
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Overlay {
+    /// The rule stacks of every key which has been pushed or popped
+    stacks: BTreeMap<String, Stack>,
+    /// The selection state of every key chosen from or changed
+    selection_state: BTreeMap<String, SelectionState>,
+}
+
+/// A ruleset on a rule stack. Rulesets pushed during an execution are shared
+/// with the overlay, so that they can be executed while the overlay changes
+/// without being copied
+#[derive(Debug, Clone)]
+pub(crate) enum RulesetRef<'a> {
+    /// A ruleset of the grammar itself
+    Grammar(&'a [Rule]),
+    /// A ruleset pushed during the execution
+    Pushed(Arc<[Rule]>),
+}
+
+impl Deref for RulesetRef<'_> {
+    type Target = [Rule];
+
+    fn deref(&self) -> &[Rule] {
+        match self {
+            RulesetRef::Grammar(rules) => rules,
+            RulesetRef::Pushed(rules) => rules,
+        }
+    }
+}
+
+impl Overlay {
+    /// Gets the topmost ruleset for a key
+    pub(crate) fn get_rule<'a>(&self, grammar: &'a Grammar, key: &str) -> Option<RulesetRef<'a>> {
+        match self.stacks.get(key) {
+            Some(stack) => match stack.pushed.last() {
+                Some(rules) => Some(RulesetRef::Pushed(Arc::clone(rules))),
+                None => stack
+                    .base
+                    .checked_sub(1)
+                    .map(|i| RulesetRef::Grammar(&grammar.rule_stack(key)[i])),
+            },
+            None => grammar
+                .rule_stack(key)
+                .last()
+                .map(|rules| RulesetRef::Grammar(rules)),
+        }
+    }
+
+    /// Gets the number of rulesets on the rule stack for a key
+    pub(crate) fn depth(&self, grammar: &Grammar, key: &str) -> usize {
+        match self.stacks.get(key) {
+            Some(stack) => stack.depth(),
+            None => grammar.rule_stack(key).len(),
+        }
+    }
+
+    /// Gets the ruleset at the given index of the rule stack for a key, where
+    /// the index is less than the depth of the stack
+    pub(crate) fn ruleset<'a>(
+        &'a self,
+        grammar: &'a Grammar,
+        key: &str,
+        index: usize,
+    ) -> &'a [Rule] {
+        match self.stacks.get(key) {
+            Some(stack) if index >= stack.base => &stack.pushed[index - stack.base],
+            _ => &grammar.rule_stack(key)[index],
+        }
+    }
+
+    /// Returns whether the given key was removed by popping its last rule
+    pub(crate) fn was_popped(&self, grammar: &Grammar, key: &str) -> bool {
+        match self.stacks.get(key) {
+            Some(stack) => stack.popped,
+            None => grammar.was_popped(key),
+        }
+    }
+
+    /// Gets the changed rule stack for a key, starting from the grammar's
+    fn stack_mut(&mut self, grammar: &Grammar, key: String) -> &mut Stack {
+        self.stacks.entry(key).or_insert_with_key(|key| Stack {
+            base: grammar.rule_stack(key).len(),
+            pushed: Vec::new(),
+            popped: grammar.was_popped(key),
+        })
+    }
+
+    /// Pushes a new ruleset onto the rule stack for a given key, made up of the
+    /// given plain text rules
+    pub(crate) fn push_rule(&mut self, grammar: &Grammar, key: String, rule_strs: Vec<String>) {
+        let rules: Arc<[Rule]> = rule_strs
+            .into_iter()
+            .map(|s| Rule::new(vec![Node::from(s)]))
+            .collect();
+        self.selection_state
+            .insert(key.clone(), SelectionState::default());
+        let stack = self.stack_mut(grammar, key);
+        stack.pushed.push(rules);
+        stack.popped = false;
+    }
+
+    /// Pops a ruleset off the rule stack for a given key, removing the key
+    /// entirely if there are no rulesets left
+    pub(crate) fn pop_rule(&mut self, grammar: &Grammar, key: String) {
+        self.selection_state
+            .insert(key.clone(), SelectionState::default());
+        if self.depth(grammar, &key) == 0 {
+            return;
+        }
+        let stack = self.stack_mut(grammar, key);
+        if stack.depth() < 2 {
+            *stack = Stack {
+                popped: true,
+                ..Stack::default()
+            };
+        } else if stack.pushed.pop().is_none() {
+            stack.base -= 1;
+        }
+    }
+
+    /// Chooses the index of a rule from the given ruleset, the topmost for a
+    /// key, using the key's distribution and updating its selection state.
+    /// Returns `None` if the ruleset is empty
+    pub(crate) fn choose<R>(
+        &mut self,
+        grammar: &Grammar,
+        key: &str,
+        rules: &[Rule],
+        rng: &mut R,
+    ) -> Option<usize>
+    where
+        R: ?Sized + Rng,
+    {
+        // Only allocate the key the first time it is chosen from
+        if !self.selection_state.contains_key(key) {
+            let selection = grammar.selection_state(key);
+            self.selection_state.insert(key.to_string(), selection);
+        }
+        let selection = self.selection_state.get_mut(key).unwrap();
+        grammar.distribution(key).choose(rules, selection, rng)
+    }
+
+    /// Applies these changes to the given rule stacks, which are those of the
+    /// grammar the changes were made on top of
+    pub(crate) fn apply(
+        self,
+        map: &mut BTreeMap<String, Vec<Vec<Rule>>>,
+        popped_keys: &mut BTreeSet<String>,
+        selection_state: &mut BTreeMap<String, SelectionState>,
+    ) {
+        for (key, stack) in self.stacks {
+            let mut rulesets = map.remove(&key).unwrap_or_default();
+            rulesets.truncate(stack.base);
+            rulesets.extend(stack.pushed.iter().map(|rules| rules.to_vec()));
+            if stack.popped {
+                popped_keys.insert(key.clone());
+            } else {
+                popped_keys.remove(&key);
+            }
+            if !rulesets.is_empty() {
+                map.insert(key, rulesets);
+            }
+        }
+        selection_state.extend(self.selection_state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Overlay;
+    use crate::{grammar, Grammar, Result};
+
+    /// Gets the topmost ruleset for a key, as the rules joined with `|`
+    fn top(overlay: &Overlay, grammar: &Grammar, key: &str) -> Option<String> {
+        let rules = overlay.get_rule(grammar, key)?;
+        let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+        Some(rules.join("|"))
+    }
+
+    #[test]
+    fn push_and_pop() -> Result<()> {
+        let mut g = grammar! {
+            "origin" => "[a:z]",
+            "a" => ["x", "y"]
+        }?;
+        g.execute("origin", &mut rand::thread_rng())?;
+        let mut overlay = Overlay::default();
+        assert_eq!(top(&overlay, &g, "a").as_deref(), Some("z"));
+
+        overlay.push_rule(&g, "a".into(), vec!["p".into(), "q".into()]);
+        assert_eq!(overlay.depth(&g, "a"), 3);
+        assert_eq!(top(&overlay, &g, "a").as_deref(), Some("p|q"));
+        assert_eq!(overlay.ruleset(&g, "a", 1)[0].to_string(), "z");
+
+        overlay.pop_rule(&g, "a".into());
+        overlay.pop_rule(&g, "a".into());
+        assert_eq!(top(&overlay, &g, "a").as_deref(), Some("x|y"));
+        overlay.pop_rule(&g, "a".into());
+        assert_eq!(top(&overlay, &g, "a"), None);
+        assert!(overlay.was_popped(&g, "a"));
+
+        overlay.push_rule(&g, "a".into(), vec!["w".into()]);
+        assert_eq!(overlay.depth(&g, "a"), 1);
+        assert!(!overlay.was_popped(&g, "a"));
+
+        overlay.pop_rule(&g, "b".into());
+        assert_eq!(overlay.depth(&g, "b"), 0);
+        assert!(!overlay.was_popped(&g, "b"));
+
+        // The grammar itself is unchanged
+        assert_eq!(g.rule_stack("a").len(), 2);
+        Ok(())
+    }
+}
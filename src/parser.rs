@@ -1,9 +1,11 @@
 use pest::Parser;
 use pest_derive::Parser;
 
-use crate::tag::Tag;
+use crate::tag::{Modifier, Tag};
+#[cfg(test)]
 use crate::Error;
 use crate::Node;
+use crate::ParseError;
 use crate::Rule as TRule;
 
 #[derive(Parser)]
@@ -12,29 +14,136 @@ struct TraceryParser;
 
 type PestError = pest::error::Error<Rule>;
 
-fn parse_rule<S: AsRef<str>>(s: S) -> Result<TRule, PestError> {
-    let parsed_str = TraceryParser::parse(Rule::rule, s.as_ref())?
-        .next()
-        .unwrap();
+/// The position of a parse error within the string being parsed, and the
+/// tokens expected there
+#[derive(Debug)]
+struct Failure {
+    offset: usize,
+    expected: Vec<String>,
+}
+
+impl Failure {
+    /// Moves the failure by the given number of bytes, for a failure in a
+    /// substring starting at that offset
+    fn shifted(mut self, offset: usize) -> Failure {
+        self.offset += offset;
+        self
+    }
+}
+
+impl From<PestError> for Failure {
+    fn from(e: PestError) -> Failure {
+        use pest::error::{ErrorVariant, InputLocation};
+        let offset = match e.location {
+            InputLocation::Pos(pos) => pos,
+            InputLocation::Span((start, _)) => start,
+        };
+        let expected = match e.variant {
+            ErrorVariant::ParsingError { positives, .. } => {
+                positives.iter().map(|r| format!("{:?}", r)).collect()
+            }
+            ErrorVariant::CustomError { .. } => Vec::new(),
+        };
+        Failure { offset, expected }
+    }
+}
+
+/// The characters which can be escaped with a backslash in plain text, along
+/// with the backslash itself
+const TEXT_SPECIAL: &[char] = &['#', '[', ']'];
+
+/// The characters which can be escaped with a backslash in the values of a
+/// labeled action, where commas separate the values
+const VALUE_SPECIAL: &[char] = &['#', '[', ']', ','];
+
+/// The characters which can be escaped with a backslash in a key, including
+/// the label of an action
+const KEY_SPECIAL: &[char] = &['#', '[', ']', ':', '.'];
+
+/// Replaces each escape sequence of a backslash followed by a backslash or one
+/// of the given characters with the character it escapes. Other backslashes
+/// are left as they are
+fn unescape(s: &str, special: &[char]) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&next) if c == '\\' && (next == '\\' || special.contains(&next)) => {
+                unescaped.push(next);
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Escapes every occurrence of the given characters, and every backslash, so
+/// that the string parses back to itself
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes plain text for use in a rule
+pub(crate) fn escape_text(s: &str) -> String {
+    escape(s, TEXT_SPECIAL)
+}
+
+/// Escapes plain text for use in one of the values of a labeled action, where
+/// commas separate the values
+pub(crate) fn escape_value(s: &str) -> String {
+    escape(s, VALUE_SPECIAL)
+}
+
+/// Escapes a key for use in a tag or as the label of an action
+pub(crate) fn escape_key(s: &str) -> String {
+    escape(s, KEY_SPECIAL)
+}
+
+/// Parses a rule, or with `Rule::value`, one of the values of an action
+fn parse_nodes(s: &str, start: Rule) -> Result<TRule, Failure> {
+    let parsed_str = TraceryParser::parse(start, s)?.next().unwrap();
 
     let nodes = parsed_str.into_inner().try_fold(Vec::new(), |mut acc, p| {
         match p.as_rule() {
-            Rule::text => acc.push(Node::Text(p.as_str().to_string())),
+            Rule::text => {
+                let text = unescape(p.as_str(), TEXT_SPECIAL);
+                // A "[" which does not begin an action is parsed separately
+                // from the text around it
+                match acc.last_mut() {
+                    Some(Node::Text(last)) => last.push_str(&text),
+                    _ => acc.push(Node::Text(text)),
+                }
+            }
+            Rule::value_text => acc.push(Node::Text(unescape(p.as_str(), VALUE_SPECIAL))),
             Rule::tag => acc.push(Node::Tag(parse_tag_pair(p)?)),
             Rule::actions => acc.push(Node::Tag(parse_actions(p)?)),
+            Rule::EOI => {}
             _ => unreachable!(),
         }
-        Ok(acc)
+        Ok::<_, Failure>(acc)
     })?;
 
     Ok(TRule::new(nodes))
 }
 
-pub(crate) fn parse_str<S: AsRef<str>>(s: S) -> Result<TRule, Error> {
-    parse_rule(s).map_err(|e| Error::ParseError(format!("{}", e)))
+fn parse_rule<S: AsRef<str>>(s: S) -> Result<TRule, Failure> {
+    parse_nodes(s.as_ref(), Rule::rule)
 }
 
-fn parse_actions(a: pest::iterators::Pair<Rule>) -> Result<Tag, PestError> {
+pub(crate) fn parse_str<S: AsRef<str>>(s: S) -> Result<TRule, ParseError> {
+    let s = s.as_ref();
+    parse_rule(s).map_err(|f| ParseError::new(s, f.offset, f.expected))
+}
+
+fn parse_actions(a: pest::iterators::Pair<Rule>) -> Result<Tag, Failure> {
     let actions = a.into_inner().try_fold(Vec::new(), |mut acc, p| {
         match p.as_rule() {
             Rule::action => {
@@ -43,33 +152,42 @@ fn parse_actions(a: pest::iterators::Pair<Rule>) -> Result<Tag, PestError> {
             }
             _ => unreachable!(),
         }
-        Ok(acc)
+        Ok::<_, Failure>(acc)
     })?;
     Ok(Tag::empty().with_actions(actions))
 }
 
-fn parse_action(a: pest::iterators::Pair<Rule>) -> Result<(Option<String>, TRule), PestError> {
+fn parse_action(a: pest::iterators::Pair<Rule>) -> Result<(Option<String>, Vec<TRule>), Failure> {
+    let offset = a.as_span().start();
     let mut tagname = None;
-    let mut rule = None;
+    let mut rules = Vec::new();
     for part in a.into_inner() {
         match part.as_rule() {
             Rule::tagname => {
-                tagname = Some(part.as_str().to_string());
+                tagname = Some(unescape(part.as_str(), KEY_SPECIAL));
             }
             // action_rhs for labeled actions; tag for unlabeled actions
             Rule::action_rhs | Rule::tag => {
-                rule = Some(parse_rule(part.as_str())?);
+                let start = part.as_span().start();
+                let rule = parse_nodes(part.as_str(), Rule::value);
+                rules.push(rule.map_err(|f| f.shifted(start))?);
             }
             _ => unreachable!(),
         }
     }
 
-    Ok((tagname, rule.unwrap()))
+    if rules.is_empty() {
+        return Err(Failure {
+            offset,
+            expected: vec![format!("{:?}", Rule::action_rhs)],
+        });
+    }
+    Ok((tagname, rules))
 }
 
-fn parse_tag_pair(s: pest::iterators::Pair<Rule>) -> Result<Tag, PestError> {
+fn parse_tag_pair(s: pest::iterators::Pair<Rule>) -> Result<Tag, Failure> {
     let mut actions = Vec::new();
-    let mut tagname = "";
+    let mut tagname = None;
     let mut modifiers = Vec::new();
     for part in s.into_inner() {
         match part.as_rule() {
@@ -78,28 +196,48 @@ fn parse_tag_pair(s: pest::iterators::Pair<Rule>) -> Result<Tag, PestError> {
                 actions.push((key, action));
             }
             Rule::tagname => {
-                tagname = part.as_str();
+                tagname = Some(unescape(part.as_str(), KEY_SPECIAL));
             }
             Rule::modifier => {
-                let modifier = part.into_inner().next().unwrap().as_str();
-                modifiers.push(modifier);
+                modifiers.push(parse_modifier(part));
             }
             _ => unreachable!(),
         }
     }
 
-    Ok(Tag::new(tagname)
-        .with_actions(actions)
-        .with_modifiers(modifiers))
+    let tag = match tagname {
+        Some(tagname) => Tag::new(tagname),
+        None => Tag::empty(),
+    };
+    Ok(tag.with_actions(actions).with_modifiers(modifiers))
+}
+
+fn parse_modifier(m: pest::iterators::Pair<Rule>) -> Modifier {
+    let mut name = "";
+    let mut args = Vec::new();
+    for part in m.into_inner() {
+        match part.as_rule() {
+            Rule::modifier_name => {
+                name = part.as_str();
+            }
+            Rule::modifier_args => {
+                args = part.into_inner().map(|a| a.as_str().to_string()).collect();
+            }
+            _ => unreachable!(),
+        }
+    }
+    Modifier::new(name, args)
 }
 
 #[cfg(test)]
 pub(crate) fn parse_tag<S: AsRef<str>>(s: S) -> Result<Tag, Error> {
-    let tag_pair = TraceryParser::parse(Rule::tag, s.as_ref())
-        .map_err(|e| Error::ParseError(format!("{}", e)))?
+    let s = s.as_ref();
+    let to_error = |f: Failure| Error::ParseError(ParseError::new(s, f.offset, f.expected));
+    let tag_pair = TraceryParser::parse(Rule::tag, s)
+        .map_err(|e| to_error(e.into()))?
         .next()
         .unwrap();
-    parse_tag_pair(tag_pair).map_err(|e| Error::ParseError(format!("{}", e)))
+    parse_tag_pair(tag_pair).map_err(to_error)
 }
 
 #[cfg(test)]
@@ -109,7 +247,7 @@ mod tests {
     #[test]
     fn parse_tagname() -> Result<(), Error> {
         let tag = parse_tag("#one#")?;
-        assert_eq!(tag.key.unwrap(), "one");
+        assert_eq!(tag.key.as_deref(), Some("one"));
         Ok(())
     }
 
@@ -117,44 +255,63 @@ mod tests {
     fn parse_text() -> Result<(), Error> {
         let src = "this is some text";
         let rule = parse_str(src)?;
-        assert_eq!(rule.0, vec![Node::Text(src.to_string())]);
+        assert_eq!(rule.nodes, vec![Node::Text(src.to_string())]);
         Ok(())
     }
 
     #[test]
     fn parse_tag_with_tag_action() -> Result<(), Error> {
         let tag = parse_tag("#[one:#two#]tagname#")?;
-        assert_eq!(tag.key.unwrap(), "tagname");
+        assert_eq!(tag.key.as_deref(), Some("tagname"));
         assert_eq!(tag.actions.len(), 1);
         let action = &tag.actions[0];
         assert_eq!(action.label, Some(String::from("one")));
-        assert_eq!((action.rule).0, vec![Node::Tag(Tag::new("two"))]);
+        assert_eq!(action.rules[0].nodes, vec![Node::Tag(Tag::new("two"))]);
         Ok(())
     }
 
     #[test]
     fn parse_tag_with_text_action() -> Result<(), Error> {
         let tag = parse_tag("#[one:a:b.c d]tagname#")?;
-        assert_eq!(tag.key.unwrap(), "tagname");
+        assert_eq!(tag.key.as_deref(), Some("tagname"));
         assert_eq!(tag.actions.len(), 1);
         let action = &tag.actions[0];
         assert_eq!(action.label, Some(String::from("one")));
-        assert_eq!((action.rule).0, vec![Node::Text("a:b.c d".to_string())]);
+        assert_eq!(
+            action.rules[0].nodes,
+            vec![Node::Text("a:b.c d".to_string())]
+        );
         Ok(())
     }
 
     #[test]
     fn parse_tag_with_modifiers() -> Result<(), Error> {
         let tag = parse_tag("#one.two.three#")?;
-        assert_eq!(tag.key.unwrap(), "one");
+        assert_eq!(tag.key.as_deref(), Some("one"));
         assert_eq!(tag.modifiers, vec!["two", "three"]);
         Ok(())
     }
 
+    #[test]
+    fn parse_tag_with_modifier_args() -> Result<(), Error> {
+        let tag = parse_tag("#one.replace(a,e).truncate(20).two().three(, x.y)#")?;
+        assert_eq!(tag.key.as_deref(), Some("one"));
+        assert_eq!(
+            tag.modifiers,
+            vec![
+                Modifier::new("replace", vec!["a".into(), "e".into()]),
+                Modifier::new("truncate", vec!["20".into()]),
+                Modifier::new("two", vec![]),
+                Modifier::new("three", vec!["".into(), " x.y".into()]),
+            ]
+        );
+        Ok(())
+    }
+
     #[test]
     fn parse_tag_complicated() -> Result<(), Error> {
         let tag = parse_tag("#[e:#[a:#b.c#]d#][f:#g.h#]i.j.k#")?;
-        assert_eq!(tag.key.unwrap(), "i");
+        assert_eq!(tag.key.as_deref(), Some("i"));
         assert_eq!(tag.modifiers, vec!["j", "k"]);
         Ok(())
     }
@@ -164,7 +321,7 @@ mod tests {
         let rule = parse_str("hello. [a][b]: #name# more after")?;
 
         assert_eq!(
-            rule.0,
+            rule.nodes,
             vec![
                 Node::Text("hello. [a][b]: ".to_string()),
                 Node::Tag(Tag::new("name")),
@@ -175,13 +332,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn parse_bare_actions() -> Result<(), Error> {
+        let rule = parse_str("hello [a:b][c:POP] #a# [not an action] [d:#a#]")?;
+        let action = |label: &str, rule: &str| (Some(label.to_string()), parse_str(rule).unwrap());
+        assert_eq!(
+            rule.nodes,
+            vec![
+                Node::Text("hello ".into()),
+                Node::Tag(Tag::empty().with_actions(vec![action("a", "b"), action("c", "POP")])),
+                Node::Text(" ".into()),
+                Node::Tag(Tag::new("a")),
+                Node::Text(" [not an action] ".into()),
+                Node::Tag(Tag::empty().with_actions(vec![action("d", "#a#")])),
+            ]
+        );
+
+        assert_eq!(parse_str("")?.nodes, vec![]);
+        assert!(parse_str("trailing #").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_large_unclosed_actions() -> Result<(), Error> {
+        // Every "[" looks like the start of an action which is never closed.
+        // Each must only be tried as an action once, or parsing is quadratic
+        let err = parse_str("[a:#b#".repeat(4000)).unwrap_err();
+        assert_eq!(err.offset, 6);
+
+        let rule = parse_str("[#b# ".repeat(4000))?;
+        assert_eq!(rule.nodes.len(), 8001);
+        assert_eq!(rule.nodes[0], Node::Text("[".into()));
+        assert_eq!(rule.nodes[1], Node::Tag(Tag::new("b")));
+        Ok(())
+    }
+
     #[test]
     fn parse_rule_with_hash_dot() -> Result<(), Error> {
         let src = "#hero# traveled with her pet #heroPet#.  #hero# was never #mood#, for the \
                    #heroPet# was always too #mood#.";
         let rule = parse_str(src)?;
         assert_eq!(
-            rule.0,
+            rule.nodes,
             vec![
                 Node::Tag(Tag::new("hero")),
                 Node::Text(" traveled with her pet ".into()),
@@ -202,6 +394,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn parse_tag_multi_action() -> Result<(), Error> {
         let src = "#[one:#two#][three:#four#]tagname.s.capitalize#";
         let mut actions = Vec::new();
@@ -216,4 +409,101 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn parse_error_position() {
+        let err = parse_str("#tool.#").unwrap_err();
+        assert_eq!((err.offset, err.line, err.column), (6, 1, 7));
+        assert_eq!(err.expected, vec!["modifier_name"]);
+        assert_eq!(err.key, None);
+
+        // Errors inside actions are relative to the whole rule
+        let err = parse_str("#[x:#[y:é#z.#]a#]b#").unwrap_err();
+        assert_eq!((err.offset, err.line, err.column), (13, 1, 13));
+        assert_eq!(err.expected, vec!["modifier_name"]);
+    }
+
+    #[test]
+    fn parse_malformed_bare_actions() {
+        let err = parse_str("hello [a:x,] there").unwrap_err();
+        assert_eq!(err.offset, 11);
+        assert!(parse_str("unclosed [a:b").is_err());
+        assert!(parse_str("[a:#b#]#c# [d:").is_err());
+
+        // Brackets which can't begin a labeled action are plain text
+        let rule = parse_str("[1] [x] [ [#a#").unwrap();
+        assert_eq!(rule.nodes[0], Node::Text("[1] [x] [ [".into()));
+    }
+
+    #[test]
+    fn parse_escapes() -> Result<(), Error> {
+        let rule = parse_str(r"\#tracery \[not an action\] a\b\\")?;
+        assert_eq!(
+            rule.nodes,
+            vec![Node::Text(r"#tracery [not an action] a\b\".to_string())]
+        );
+
+        // Escapes which are only needed in keys or action values are left as
+        // they are in plain text
+        let rule = parse_str(r"a\:b\.c\,d")?;
+        assert_eq!(rule.nodes, vec![Node::Text(r"a\:b\.c\,d".to_string())]);
+
+        let tag = parse_tag(r"#[a\:b:x\]y\#z\\]c\.d\#e.capitalize#")?;
+        assert_eq!(tag.key.as_deref(), Some("c.d#e"));
+        assert_eq!(tag.modifiers, vec!["capitalize"]);
+        let action = &tag.actions[0];
+        assert_eq!(action.label.as_deref(), Some("a:b"));
+        assert_eq!(
+            action.rules[0].nodes,
+            vec![Node::Text(r"x]y#z\".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_action_only_tag_and_values() -> Result<(), Error> {
+        let tag = parse_tag("#[hero:#name#][animal:cat,#bird.s#,owl\\,ish]#")?;
+        assert_eq!(tag.key, None);
+        assert_eq!(tag.actions.len(), 2);
+        let values: Vec<String> = tag.actions[1].rules.iter().map(|r| r.to_string()).collect();
+        assert_eq!(values, vec!["cat", "#bird.s#", "owl,ish"]);
+        assert_eq!(
+            tag.to_string(),
+            "[hero:#name#][animal:cat,#bird.s#,owl\\,ish]"
+        );
+
+        assert!(parse_tag("##").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unparse_round_trip() -> Result<(), Error> {
+        let sources = [
+            "plain text",
+            r"\#hashtag and \[brackets\] and a\\backslash",
+            "#a.b.c(1,2)# and #[x:#y#][z:POP][#w#]v.s#",
+            r"[a:x\]y][b:\#][c:POP]",
+            r"#[a:x,y\,z,#b.replace(c,d)#]# and, with commas",
+            r"#c\.d\#e# is a key with \\ reserved characters",
+            "text with : and . in it",
+            r"text with \: and \. and \, in it",
+            "#[a:#[b:c]#]x#",
+            "[a:#[b:c]#]",
+            "#[#[b:c]#]x#",
+            "[#[b:c]#]",
+        ];
+        for source in sources.iter() {
+            let rule = parse_str(source)?;
+            let unparsed = rule.to_string();
+            assert_eq!(
+                parse_str(&unparsed)?,
+                rule,
+                "{} unparsed as {}",
+                source,
+                unparsed
+            );
+        }
+        assert_eq!(parse_str(r"a\b\#")?.to_string(), r"a\\b\#");
+        Ok(())
+    }
 }
@@ -1,7 +1,10 @@
+use crate::tag::Tag;
 use crate::Execute;
 use crate::Grammar;
 use crate::Node;
+use crate::Output;
 use crate::Result;
+use crate::State;
 
 use lazy_static::lazy_static;
 
@@ -9,26 +12,146 @@ lazy_static! {
     static ref POP: String = String::from("POP");
 }
 
+/// A rule string along with the weight given to it when choosing between the
+/// alternatives in a ruleset.
+///
+/// Rules are chosen with probability proportional to their weight, so a rule
+/// with a weight of 2 is twice as likely to be chosen as a rule with a weight
+/// of 1. Rules with a weight of zero are never chosen, unless every rule in
+/// the ruleset has a weight of zero, in which case all of them are equally
+/// likely to be chosen.
+///
+/// Any of the types accepted by [`Grammar::from_map`] in place of a plain
+/// rule string can be converted into a `WeightedRule`: strings are given a
+/// weight of 1, while `(rule, weight)` tuples use the given weight.
+///
+/// # Examples
+/// ```
+/// use tracery::WeightedRule;
+/// # use tracery::Result;
+/// # fn main() -> Result<()> {
+/// let map = vec![
+///     ("origin", vec![WeightedRule::new("#coin#", 1)]),
+///     ("coin", vec![WeightedRule::new("heads", 3), WeightedRule::from("tails")]),
+/// ];
+/// let g = tracery::from_map(map)?;
+/// # let output = g.flatten(&mut rand::thread_rng())?;
+/// # assert!(output == "heads" || output == "tails");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Grammar::from_map`]: struct.Grammar.html#method.from_map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightedRule {
+    /// The rule string
+    pub rule: String,
+    /// The weight of the rule
+    pub weight: u32,
+}
+
+impl WeightedRule {
+    /// Creates a new rule string with the given weight
+    pub fn new<S: Into<String>>(rule: S, weight: u32) -> WeightedRule {
+        WeightedRule {
+            rule: rule.into(),
+            weight,
+        }
+    }
+}
+
+impl From<&str> for WeightedRule {
+    fn from(rule: &str) -> Self {
+        WeightedRule::new(rule, 1)
+    }
+}
+
+impl From<String> for WeightedRule {
+    fn from(rule: String) -> Self {
+        WeightedRule::new(rule, 1)
+    }
+}
+
+impl From<&String> for WeightedRule {
+    fn from(rule: &String) -> Self {
+        WeightedRule::new(rule.as_str(), 1)
+    }
+}
+
+impl From<(&str, u32)> for WeightedRule {
+    fn from((rule, weight): (&str, u32)) -> Self {
+        WeightedRule::new(rule, weight)
+    }
+}
+
+impl From<(String, u32)> for WeightedRule {
+    fn from((rule, weight): (String, u32)) -> Self {
+        WeightedRule::new(rule, weight)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct Rule(pub(crate) Vec<Node>);
+pub(crate) struct Rule {
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) weight: u32,
+}
 
 impl Rule {
     pub(crate) fn new(nodes: Vec<Node>) -> Rule {
-        Rule(nodes)
+        Rule { nodes, weight: 1 }
+    }
+
+    /// Sets the weight of this rule
+    pub(crate) fn with_weight(mut self, weight: u32) -> Rule {
+        self.weight = weight;
+        self
     }
 
     pub(crate) fn is_pop(&self) -> bool {
-        self.0.len() == 1 && self.0.first().unwrap().text() == Some(&POP)
+        self.nodes.len() == 1 && self.nodes.first().unwrap().text() == Some(&POP)
+    }
+
+    /// Calls `f` on every tag in this rule, including tags nested inside of
+    /// actions, stopping at the first error
+    pub(crate) fn try_for_each_tag<F>(&self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&Tag) -> Result<()>,
+    {
+        for node in self.nodes.iter() {
+            if let Node::Tag(tag) = node {
+                f(tag)?;
+                for rule in tag.actions.iter().flat_map(|a| &a.rules) {
+                    rule.try_for_each_tag(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.nodes.iter().try_for_each(|node| write!(f, "{}", node))
     }
 }
 
 impl Execute for Rule {
-    fn execute<R: ?Sized + rand::Rng>(&self, grammar: &mut Grammar, rng: &mut R) -> Result<String> {
-        let parts = self
-            .0
-            .iter()
-            .map(|n| n.execute(grammar, rng))
-            .collect::<Result<Vec<String>>>()?;
-        Ok(parts.join(""))
+    fn execute<R: ?Sized + rand::Rng>(
+        &self,
+        grammar: &Grammar,
+        state: &mut State,
+        out: &mut Output<'_>,
+        rng: &mut R,
+    ) -> Result<()> {
+        let start = out.len();
+        for node in self.nodes.iter() {
+            let mark = state.tracer().map(|t| t.mark());
+            let offset = out.len() - start;
+            node.execute(grammar, state, out, rng)?;
+            if let (Some(tracer), Some(mark)) = (state.tracer(), mark) {
+                tracer.shift(mark, offset);
+            }
+        }
+        Ok(())
     }
 }
@@ -1,28 +1,115 @@
-use crate::{grammar::Grammar, Error, Execute, Result, Rule};
-use rand::{seq::SliceRandom, Rng};
+use crate::parser::{escape_key, escape_value};
+use crate::{grammar::Grammar, Error, Execute, Node, Output, Result, Rule, State};
+use rand::Rng;
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) struct Action {
     pub(crate) label: Option<String>,
-    pub(crate) rule: Rule,
+    /// The rules to execute. A labeled action pushes their outputs onto the
+    /// label's rule stack as a single ruleset. An unlabeled action always has
+    /// exactly one rule
+    pub(crate) rules: Vec<Rule>,
+}
+
+impl Action {
+    /// Whether this action pops a ruleset off of its label's rule stack
+    pub(crate) fn is_pop(&self) -> bool {
+        self.label.is_some() && self.rules.len() == 1 && self.rules[0].is_pop()
+    }
 }
 
 impl From<(Option<String>, Rule)> for Action {
     fn from((label, rule): (Option<String>, Rule)) -> Self {
-        Action { label, rule }
+        Action {
+            label,
+            rules: vec![rule],
+        }
+    }
+}
+
+impl From<(Option<String>, Vec<Rule>)> for Action {
+    fn from((label, rules): (Option<String>, Vec<Rule>)) -> Self {
+        Action { label, rules }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        if let Some(label) = &self.label {
+            write!(f, "{}:", escape_key(label))?;
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            for node in &rule.nodes {
+                match node {
+                    Node::Text(s) => write!(f, "{}", escape_value(s))?,
+                    Node::Tag(tag) => tag.fmt_nested(f, true)?,
+                }
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// A modifier applied to a tag, along with any arguments passed to it
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Modifier {
+    pub(crate) name: String,
+    pub(crate) args: Vec<String>,
+}
+
+impl Modifier {
+    /// Creates a modifier with the given name and arguments
+    pub(crate) fn new<S: Into<String>>(name: S, args: Vec<String>) -> Modifier {
+        Modifier {
+            name: name.into(),
+            args,
+        }
+    }
+}
+
+impl From<&str> for Modifier {
+    fn from(name: &str) -> Self {
+        Modifier::new(name, Vec::new())
+    }
+}
+
+impl From<String> for Modifier {
+    fn from(name: String) -> Self {
+        Modifier::new(name, Vec::new())
+    }
+}
+
+impl std::fmt::Display for Modifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.args.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}({})", self.name, self.args.join(","))
+        }
+    }
+}
+
+impl PartialEq<&str> for Modifier {
+    fn eq(&self, other: &&str) -> bool {
+        self.args.is_empty() && self.name == *other
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) struct Tag {
-    pub(crate) key: Option<String>,
+    pub(crate) key: Option<Arc<str>>,
     pub(crate) actions: Vec<Action>,
-    pub(crate) modifiers: Vec<String>,
+    pub(crate) modifiers: Vec<Modifier>,
 }
 
 impl Tag {
     /// Creates a tag with the given key and no associated actions or modifiers
-    pub(crate) fn new<S: Into<String>>(key: S) -> Tag {
+    pub(crate) fn new<S: Into<Arc<str>>>(key: S) -> Tag {
         Tag {
             key: Some(key.into()),
             actions: Vec::new(),
@@ -38,33 +125,58 @@ impl Tag {
         }
     }
 
+    /// Expands this Tag's key into a new string, for when the whole
+    /// expansion is needed at once
     pub(crate) fn get_rule<R: ?Sized + Rng>(
         &self,
-        grammar: &mut Grammar,
+        grammar: &Grammar,
+        state: &mut State,
         rng: &mut R,
     ) -> Result<String> {
-        match &self.key {
-            Some(key) => {
-                let rule = match grammar.get_rule(key) {
-                    Some(rules) => Ok(rules.choose(rng).unwrap().clone()),
-                    None => Err(Error::MissingKeyError(key.clone())),
-                }?;
-                rule.execute(grammar, rng)
-            }
-            None => Ok(String::default()),
+        let mut output = String::new();
+        if let Some(key) = &self.key {
+            let mut out = Output::new(&mut output, state.max_output_bytes());
+            grammar.expand(key, state, &mut out, rng)?;
         }
+        Ok(output)
     }
 
     /// Applies the modifiers associated with this Tag to a given string, using
-    /// the definitions in the given Grammar
-    pub(crate) fn apply_modifiers(&self, s: &str, grammar: &Grammar) -> String {
+    /// the definitions in the given Grammar. Unknown modifiers are skipped,
+    /// unless the Grammar uses strict modifiers
+    pub(crate) fn apply_modifiers(&self, s: &str, grammar: &Grammar) -> Result<String> {
         let mut string = String::from(s);
         for modifier in self.modifiers.iter() {
-            if let Some(f) = grammar.get_modifier(modifier) {
-                string = f(&string);
+            match grammar.get_modifier(&modifier.name) {
+                Some(f) => {
+                    let args: Vec<&str> = modifier.args.iter().map(|a| a.as_str()).collect();
+                    string = f(&string, &args);
+                }
+                None if grammar.strict_modifiers() => return Err(self.unknown_modifier(modifier)),
+                None => {}
             }
         }
-        string
+        Ok(string)
+    }
+
+    /// Checks that every modifier used by this Tag is registered with the
+    /// given Grammar
+    pub(crate) fn check_modifiers(&self, grammar: &Grammar) -> Result<()> {
+        match self
+            .modifiers
+            .iter()
+            .find(|m| grammar.get_modifier(&m.name).is_none())
+        {
+            Some(modifier) => Err(self.unknown_modifier(modifier)),
+            None => Ok(()),
+        }
+    }
+
+    fn unknown_modifier(&self, modifier: &Modifier) -> Error {
+        Error::UnknownModifier {
+            key: self.key.as_deref().unwrap_or_default().to_string(),
+            modifier: modifier.name.clone(),
+        }
     }
 
     /// Adds the given actions to this tag
@@ -77,30 +189,146 @@ impl Tag {
     }
 
     /// Adds the given modifiers to this tag
-    pub(crate) fn with_modifiers<S: Into<String>>(mut self, modifiers: Vec<S>) -> Tag {
-        self.modifiers = modifiers.into_iter().map(|s| s.into()).collect();
+    pub(crate) fn with_modifiers<M: Into<Modifier>>(mut self, modifiers: Vec<M>) -> Tag {
+        self.modifiers = modifiers.into_iter().map(|m| m.into()).collect();
         self
     }
 }
 
+impl Tag {
+    /// Writes this tag as it would appear in a rule, or within an action if
+    /// `nested` is true
+    fn fmt_nested(&self, f: &mut std::fmt::Formatter<'_>, nested: bool) -> std::fmt::Result {
+        let actions = |f: &mut std::fmt::Formatter<'_>| {
+            self.actions.iter().try_for_each(|a| write!(f, "{}", a))
+        };
+        match &self.key {
+            Some(key) => {
+                write!(f, "#")?;
+                actions(f)?;
+                write!(f, "{}", escape_key(key))?;
+                for modifier in &self.modifiers {
+                    write!(f, ".{}", modifier)?;
+                }
+                write!(f, "#")
+            }
+            // Tags without keys are equivalent to bare actions, which can
+            // only appear at the top level of a rule
+            None if nested => {
+                write!(f, "#")?;
+                actions(f)?;
+                write!(f, "#")
+            }
+            None => actions(f),
+        }
+    }
+}
+
+impl std::fmt::Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_nested(f, false)
+    }
+}
+
 impl Execute for Tag {
-    fn execute<R: ?Sized + Rng>(&self, grammar: &mut Grammar, rng: &mut R) -> Result<String> {
+    fn execute<R: ?Sized + Rng>(
+        &self,
+        grammar: &Grammar,
+        state: &mut State,
+        out: &mut Output<'_>,
+        rng: &mut R,
+    ) -> Result<()> {
+        if let Some(tracer) = state.tracer() {
+            tracer.begin_tag();
+        }
+        if !self.actions.is_empty() {
+            self.run_actions(grammar, state, rng)?;
+        }
+
+        // Without modifiers, the expansion can be written straight to the
+        // output. Modifiers need the whole expansion, so it is buffered
+        let (changed, len) = match &self.key {
+            Some(key) if self.modifiers.is_empty() => {
+                let start = out.len();
+                grammar.expand(key, state, out, rng)?;
+                (false, out.len() - start)
+            }
+            _ => self.write_modified(grammar, state, out, rng)?,
+        };
+
+        if state.tracer().is_some() {
+            self.trace_end(state, changed, len);
+        }
+        Ok(())
+    }
+}
+
+// The steps of executing a tag are kept in separate functions, so that the
+// stack frame of `execute`, which is on the stack once for every level of
+// nesting, stays small
+impl Tag {
+    /// Runs the actions of this tag, in order
+    fn run_actions<R: ?Sized + Rng>(
+        &self,
+        grammar: &Grammar,
+        state: &mut State,
+        rng: &mut R,
+    ) -> Result<()> {
         for action in &self.actions {
-            if action.rule.is_pop() && action.label.is_some() {
-                grammar.pop_rule(action.label.as_ref().unwrap().clone());
-            } else {
-                let output = action.rule.execute(grammar, rng)?;
-                if action.label.is_some() {
-                    grammar.push_rule(action.label.as_ref().unwrap().clone(), output);
+            state.count_action()?;
+            match &action.label {
+                Some(label) if action.is_pop() => {
+                    state.pop_rule(grammar, label.clone());
+                    if let Some(tracer) = state.tracer() {
+                        tracer.pop_action(label);
+                    }
+                }
+                label => {
+                    let mut outputs = Vec::with_capacity(action.rules.len());
+                    for rule in &action.rules {
+                        if let Some(tracer) = state.tracer() {
+                            tracer.begin_rule();
+                        }
+                        let mut output = String::new();
+                        let mut value = Output::new(&mut output, state.max_output_bytes());
+                        rule.execute(grammar, state, &mut value, rng)?;
+                        outputs.push(output);
+                    }
+                    if let Some(tracer) = state.tracer() {
+                        tracer.end_action(label.as_ref(), &outputs);
+                    }
+                    if let Some(label) = label {
+                        state.count_push()?;
+                        state.push_rule(grammar, label.clone(), outputs);
+                    }
                 }
             }
         }
+        Ok(())
+    }
 
-        let choice = self.get_rule(grammar, rng)?;
-
-        let modified = self.apply_modifiers(&choice, grammar);
+    /// Expands this tag's key into a buffer, applies the modifiers, and writes
+    /// the result. Returns whether the modifiers changed the expansion, and the
+    /// length of the result
+    fn write_modified<R: ?Sized + Rng>(
+        &self,
+        grammar: &Grammar,
+        state: &mut State,
+        out: &mut Output<'_>,
+        rng: &mut R,
+    ) -> Result<(bool, usize)> {
+        let choice = self.get_rule(grammar, state, rng)?;
+        let modified = self.apply_modifiers(&choice, grammar)?;
+        out.write(&modified)?;
+        Ok((choice != modified, modified.len()))
+    }
 
-        Ok(modified)
+    /// Records the end of this tag's execution in the state's tracer
+    fn trace_end(&self, state: &mut State, changed: bool, len: usize) {
+        if let Some(tracer) = state.tracer() {
+            let modifiers = self.modifiers.iter().map(|m| m.to_string()).collect();
+            tracer.end_tag(self.key.is_some(), modifiers, changed, len);
+        }
     }
 }
 
@@ -113,9 +341,9 @@ mod tests {
     #[test]
     fn get_rule_from_grammar() -> Result<()> {
         let input = hashmap! { "a" => vec!["b"] };
-        let mut g = Grammar::from_map(input)?;
+        let g = Grammar::from_map(input)?;
         let tag = parse_tag("#a#")?;
-        let r = tag.get_rule(&mut g, &mut rand::thread_rng())?;
+        let r = tag.get_rule(&g, &mut State::default(), &mut rand::thread_rng())?;
         assert_eq!(r, "b");
         Ok(())
     }
@@ -123,9 +351,9 @@ mod tests {
     #[test]
     fn get_rule_missing_key() -> Result<()> {
         let input = hashmap! { "a" => vec!["b"] };
-        let mut g = Grammar::from_map(input)?;
+        let g = Grammar::from_map(input)?;
         let tag = parse_tag("#b#")?;
-        let r = tag.get_rule(&mut g, &mut rand::thread_rng());
+        let r = tag.get_rule(&g, &mut State::default(), &mut rand::thread_rng());
         assert!(matches!(r, Err(Error::MissingKeyError(_))));
         Ok(())
     }
@@ -135,8 +363,34 @@ mod tests {
         let input = hashmap! { "a" => vec!["b"] };
         let g = Grammar::from_map(input)?;
         let tag = parse_tag("#b.capitalize#")?;
-        let x = tag.apply_modifiers("x", &g);
+        let x = tag.apply_modifiers("x", &g)?;
         assert_eq!(x, "X");
         Ok(())
     }
+
+    #[test]
+    fn apply_modifiers_with_args() -> Result<()> {
+        let input = hashmap! { "a" => vec!["b"] };
+        let g = Grammar::from_map(input)?;
+        let tag = parse_tag("#b.replace(a,e).capitalize#")?;
+        let x = tag.apply_modifiers("banana", &g)?;
+        assert_eq!(x, "Benene");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_unknown_modifiers() -> Result<()> {
+        let input = hashmap! { "a" => vec!["b"] };
+        let mut g = Grammar::from_map(input)?;
+        let tag = parse_tag("#b.capitalise.s#")?;
+        assert_eq!(tag.apply_modifiers("x", &g)?, "xes");
+
+        g.set_strict_modifiers(true);
+        let x = tag.apply_modifiers("x", &g);
+        assert!(matches!(
+            x,
+            Err(Error::UnknownModifier { key, modifier }) if key == "b" && modifier == "capitalise"
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,257 @@
+use std::ops::Range;
+
+/// The output of an execution, along with a tree of the expansions which
+/// produced it. Created by [`Grammar::execute_traced`].
+///
+/// [`Grammar::execute_traced`]: struct.Grammar.html#method.execute_traced
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Trace {
+    /// The output string
+    pub output: String,
+    /// The expansion of the executed key
+    pub root: TraceNode,
+    /// The index of the rule chosen by each expansion, in the order the
+    /// choices were made. Passing these to [`Grammar::replay`] reproduces the
+    /// output
+    ///
+    /// [`Grammar::replay`]: struct.Grammar.html#method.replay
+    pub choices: Vec<usize>,
+}
+
+/// A record of the expansion of a single key
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TraceNode {
+    /// The key which was expanded
+    pub key: String,
+    /// The index of the rule chosen from the key's ruleset
+    pub choice: usize,
+    /// Whether the key was missing, and was rendered according to the
+    /// grammar's [`MissingKeyPolicy`] instead of being expanded. Missing keys
+    /// have no rule chosen, and have a `choice` of 0
+    ///
+    /// [`MissingKeyPolicy`]: enum.MissingKeyPolicy.html
+    pub missing: bool,
+    /// The actions run while expanding the key, in the order they were run.
+    /// This includes the actions of the tag which expanded the key, followed
+    /// by any actions in the chosen rule which are not part of another tag
+    pub actions: Vec<TraceAction>,
+    /// The modifiers applied to the expansion, in the order they were applied,
+    /// including any arguments, e.g. `replace(a,e)`
+    pub modifiers: Vec<String>,
+    /// The range of bytes in the output string produced by this expansion,
+    /// after modifiers were applied. If the modifiers changed the expansion,
+    /// the nested expansions are all given this same range, since they no
+    /// longer correspond to any specific part of the output
+    pub range: Range<usize>,
+    /// The expansions of the tags in the chosen rule, in order
+    pub children: Vec<TraceNode>,
+}
+
+/// A record of an action run during an execution
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceAction {
+    /// A labeled action which pushed a ruleset onto a key's rule stack
+    Push {
+        /// The key the ruleset was pushed onto
+        key: String,
+        /// The rules in the ruleset which was pushed, one for each
+        /// comma-separated value of the action
+        values: Vec<String>,
+        /// The expansions which produced each value, in the same order as
+        /// `values`. Their ranges are relative to the value, rather than to
+        /// the output string
+        expansions: Vec<Vec<TraceNode>>,
+    },
+    /// A labeled action which popped a rule off of a key's rule stack
+    Pop {
+        /// The key the rule was popped off of
+        key: String,
+    },
+    /// An unlabeled action, whose output was discarded
+    Unlabeled {
+        /// The discarded output of the action
+        output: String,
+        /// The expansions which produced the output. Their ranges are relative
+        /// to the discarded output, rather than to the output string
+        expansions: Vec<TraceNode>,
+    },
+}
+
+/// The expansions and actions recorded while executing a single rule
+#[derive(Debug, Default)]
+struct Frame {
+    children: Vec<TraceNode>,
+    actions: Vec<TraceAction>,
+}
+
+/// Builds a trace over the course of an execution. Nodes are created with
+/// ranges relative to the output of the rule containing them, and are
+/// converted to absolute ranges once the execution is finished
+#[derive(Debug)]
+pub(crate) struct Tracer {
+    /// The rules currently being executed, innermost last
+    frames: Vec<Frame>,
+    /// The actions run by the tags currently being executed, innermost last
+    pending: Vec<Vec<TraceAction>>,
+    /// The index of each rule chosen so far
+    choices: Vec<usize>,
+}
+
+impl Tracer {
+    pub(crate) fn new() -> Tracer {
+        Tracer {
+            frames: vec![Frame::default()],
+            pending: Vec::new(),
+            choices: Vec::new(),
+        }
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    /// Records the start of the execution of a rule
+    pub(crate) fn begin_rule(&mut self) {
+        self.frames.push(Frame::default());
+    }
+
+    /// Records the choice of a rule when expanding a key
+    pub(crate) fn choose(&mut self, choice: usize) {
+        self.choices.push(choice);
+    }
+
+    /// Records the end of the expansion of a key, whose rule was begun with
+    /// `begin_rule`
+    pub(crate) fn end_expansion(&mut self, key: &str, choice: usize, len: usize) {
+        let frame = self.frames.pop().unwrap();
+        self.frame().children.push(TraceNode {
+            key: key.to_string(),
+            choice,
+            missing: false,
+            actions: frame.actions,
+            modifiers: Vec::new(),
+            range: 0..len,
+            children: frame.children,
+        });
+    }
+
+    /// Records a missing key, rendered as a string of the given length
+    pub(crate) fn missing(&mut self, key: &str, len: usize) {
+        self.frame().children.push(TraceNode {
+            key: key.to_string(),
+            choice: 0,
+            missing: true,
+            actions: Vec::new(),
+            modifiers: Vec::new(),
+            range: 0..len,
+            children: Vec::new(),
+        });
+    }
+
+    /// Returns a marker for the expansions recorded so far in the current rule
+    pub(crate) fn mark(&mut self) -> usize {
+        self.frame().children.len()
+    }
+
+    /// Offsets the ranges of the expansions recorded in the current rule since
+    /// the given mark
+    pub(crate) fn shift(&mut self, mark: usize, offset: usize) {
+        for child in self.frame().children[mark..].iter_mut() {
+            child.range = child.range.start + offset..child.range.end + offset;
+        }
+    }
+
+    /// Records the start of the execution of a tag
+    pub(crate) fn begin_tag(&mut self) {
+        self.pending.push(Vec::new());
+    }
+
+    /// Records a pop action run by the current tag
+    pub(crate) fn pop_action(&mut self, key: &str) {
+        self.pending.last_mut().unwrap().push(TraceAction::Pop {
+            key: key.to_string(),
+        });
+    }
+
+    /// Records a labeled or unlabeled action run by the current tag, given the
+    /// output of each of its rules, which were each begun with `begin_rule`
+    pub(crate) fn end_action(&mut self, label: Option<&String>, outputs: &[String]) {
+        let frames = self.frames.split_off(self.frames.len() - outputs.len());
+        let pending = self.pending.last_mut().unwrap();
+        let mut expansions = Vec::with_capacity(frames.len());
+        for frame in frames {
+            pending.extend(frame.actions);
+            expansions.push(frame.children);
+        }
+        pending.push(match label {
+            Some(key) => TraceAction::Push {
+                key: key.clone(),
+                values: outputs.to_vec(),
+                expansions,
+            },
+            None => TraceAction::Unlabeled {
+                output: outputs.concat(),
+                expansions: expansions.into_iter().flatten().collect(),
+            },
+        });
+    }
+
+    /// Records the end of the execution of a tag. If the tag expanded a key,
+    /// the tag's actions and modifiers are added to the key's expansion.
+    /// Otherwise, the actions are added to the current rule
+    pub(crate) fn end_tag(
+        &mut self,
+        expanded: bool,
+        modifiers: Vec<String>,
+        changed: bool,
+        len: usize,
+    ) {
+        let mut actions = self.pending.pop().unwrap();
+        let frame = self.frame();
+        match frame.children.last_mut() {
+            Some(node) if expanded => {
+                actions.append(&mut node.actions);
+                node.actions = actions;
+                node.modifiers = modifiers;
+                node.range = 0..len;
+                if changed {
+                    collapse(&mut node.children, len);
+                }
+            }
+            _ => frame.actions.append(&mut actions),
+        }
+    }
+
+    /// Finishes the trace for the given output
+    pub(crate) fn finish(mut self, output: String) -> Trace {
+        let mut root = self.frame().children.pop().unwrap();
+        absolutize(&mut root, 0);
+        Trace {
+            output,
+            root,
+            choices: self.choices,
+        }
+    }
+}
+
+/// Gives the given expansions, and all of their descendants, the range
+/// `0..len` relative to their parent
+fn collapse(nodes: &mut [TraceNode], len: usize) {
+    for node in nodes.iter_mut() {
+        node.range = 0..len;
+        collapse(&mut node.children, len);
+    }
+}
+
+/// Converts the ranges of an expansion and its descendants from being relative
+/// to their parent to being absolute
+fn absolutize(node: &mut TraceNode, base: usize) {
+    node.range = node.range.start + base..node.range.end + base;
+    let start = node.range.start;
+    for child in node.children.iter_mut() {
+        absolutize(child, start);
+    }
+}
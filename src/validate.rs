@@ -0,0 +1,299 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt;
+
+use crate::{Grammar, Rule};
+
+/// A problem found in a [`Grammar`] by [`Grammar::validate`].
+///
+/// [`Grammar`]: struct.Grammar.html
+/// [`Grammar::validate`]: struct.Grammar.html#method.validate
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Diagnostic {
+    /// A tag references a key which is neither defined in the grammar nor
+    /// pushed by any action
+    UndefinedKey {
+        /// The undefined key
+        key: String,
+        /// The key whose rules contain the tag
+        in_key: String,
+    },
+
+    /// The grammar's default rule is not defined
+    MissingDefaultRule {
+        /// The default rule
+        key: String,
+    },
+
+    /// A key is defined, but no tag reachable from the default rule references
+    /// it
+    UnreachableKey {
+        /// The unreachable key
+        key: String,
+    },
+
+    /// A tag uses a modifier which is not registered with the grammar
+    UnknownModifier {
+        /// The name of the unknown modifier
+        modifier: String,
+        /// The key whose rules contain the tag
+        in_key: String,
+    },
+
+    /// A key has a ruleset with no rules, so it cannot be expanded
+    EmptyRuleset {
+        /// The key with the empty ruleset
+        key: String,
+    },
+
+    /// An action pops a key which no action pushes, so it removes one of the
+    /// grammar's own rulesets
+    PopNeverPushed {
+        /// The popped key
+        key: String,
+        /// The key whose rules contain the action
+        in_key: String,
+    },
+
+    /// A key contains one of the reserved characters `[`, `]`, `.`, `:` or
+    /// `#`, so tags can only reference it by escaping them with `\`
+    ReservedCharacters {
+        /// The key containing reserved characters
+        key: String,
+    },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::UndefinedKey { key, in_key } => write!(
+                f,
+                "key '{}' references '{}', which is never defined",
+                in_key, key
+            ),
+            Diagnostic::MissingDefaultRule { key } => {
+                write!(f, "default rule '{}' is not defined", key)
+            }
+            Diagnostic::UnreachableKey { key } => {
+                write!(f, "key '{}' is unreachable from the default rule", key)
+            }
+            Diagnostic::UnknownModifier { modifier, in_key } => {
+                write!(f, "key '{}' uses unknown modifier '{}'", in_key, modifier)
+            }
+            Diagnostic::EmptyRuleset { key } => write!(f, "key '{}' has no rules", key),
+            Diagnostic::PopNeverPushed { key, in_key } => {
+                write!(f, "key '{}' pops '{}', which is never pushed", in_key, key)
+            }
+            Diagnostic::ReservedCharacters { key } => write!(
+                f,
+                "key '{}' contains reserved characters, which must be escaped to reference it",
+                key
+            ),
+        }
+    }
+}
+
+/// Calls `f` on every rule in the given key's rule stack
+fn for_each_rule<F>(grammar: &Grammar, key: &str, mut f: F)
+where
+    F: FnMut(&Rule),
+{
+    grammar.rule_stack(key).iter().flatten().for_each(&mut f);
+}
+
+/// Finds every problem in the given grammar
+pub(crate) fn validate(grammar: &Grammar) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut seen = HashSet::new();
+    let mut push = |diagnostic: Diagnostic| {
+        if seen.insert(diagnostic.clone()) {
+            diagnostics.push(diagnostic);
+        }
+    };
+
+    // Collect every key which is pushed by an action
+    let mut pushed = BTreeSet::new();
+    for key in grammar.keys() {
+        for_each_rule(grammar, key, |rule| {
+            let _ = rule.try_for_each_tag(&mut |tag| {
+                for action in &tag.actions {
+                    match &action.label {
+                        Some(label) if !action.is_pop() => {
+                            pushed.insert(label.clone());
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(())
+            });
+        });
+    }
+
+    for key in grammar.keys() {
+        if key.contains(['[', ']', '.', ':', '#']) {
+            push(Diagnostic::ReservedCharacters { key: key.clone() });
+        }
+        if grammar.rule_stack(key).iter().any(Vec::is_empty) {
+            push(Diagnostic::EmptyRuleset { key: key.clone() });
+        }
+        for_each_rule(grammar, key, |rule| {
+            let _ = rule.try_for_each_tag(&mut |tag| {
+                for action in &tag.actions {
+                    match &action.label {
+                        Some(label) if action.is_pop() && !pushed.contains(label) => {
+                            push(Diagnostic::PopNeverPushed {
+                                key: label.clone(),
+                                in_key: key.clone(),
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(referenced) = &tag.key {
+                    if grammar.rule_stack(referenced).is_empty() && !pushed.contains(&**referenced)
+                    {
+                        push(Diagnostic::UndefinedKey {
+                            key: referenced.to_string(),
+                            in_key: key.clone(),
+                        });
+                    }
+                }
+                for modifier in &tag.modifiers {
+                    if grammar.get_modifier(&modifier.name).is_none() {
+                        push(Diagnostic::UnknownModifier {
+                            modifier: modifier.name.clone(),
+                            in_key: key.clone(),
+                        });
+                    }
+                }
+                Ok(())
+            });
+        });
+    }
+
+    // Walk the keys referenced from the default rule
+    let default_rule = grammar.default_rule();
+    if grammar.rule_stack(default_rule).is_empty() {
+        push(Diagnostic::MissingDefaultRule {
+            key: default_rule.to_string(),
+        });
+    }
+    let mut reachable = BTreeSet::new();
+    let mut queue = VecDeque::new();
+    reachable.insert(default_rule.to_string());
+    queue.push_back(default_rule.to_string());
+    while let Some(key) = queue.pop_front() {
+        for_each_rule(grammar, &key, |rule| {
+            let _ = rule.try_for_each_tag(&mut |tag| {
+                if let Some(referenced) = &tag.key {
+                    if reachable.insert(referenced.to_string()) {
+                        queue.push_back(referenced.to_string());
+                    }
+                }
+                Ok(())
+            });
+        });
+    }
+    for key in grammar.keys() {
+        if !reachable.contains(key) {
+            push(Diagnostic::UnreachableKey { key: key.clone() });
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Diagnostic;
+    use crate::{grammar, Grammar, Result};
+
+    #[test]
+    fn validate_clean_grammar() -> Result<()> {
+        let g = grammar! {
+            "origin" => "#[hero:#name#][pet:#animal#]story#[hero:POP][pet:POP]",
+            "story" => "#hero.capitalize# and #pet.a#",
+            "name" => ["arjun", "mia"],
+            "animal" => ["owl", "cat"]
+        }?;
+        assert_eq!(g.validate(), vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_reports_problems() -> Result<()> {
+        let empty: Vec<&str> = vec![];
+        let map = vec![
+            ("origin", vec!["#a# #[b:POP]c.shout# #[d:x]d#"]),
+            ("a", vec!["#[e:#undefined#]e#"]),
+            ("c", vec!["c"]),
+            ("empty", empty),
+            ("bad.key", vec!["x"]),
+        ];
+        let g = Grammar::from_map(map)?;
+        let diagnostics = g.validate();
+        let expected = vec![
+            Diagnostic::UndefinedKey {
+                key: "undefined".into(),
+                in_key: "a".into(),
+            },
+            Diagnostic::ReservedCharacters {
+                key: "bad.key".into(),
+            },
+            Diagnostic::EmptyRuleset {
+                key: "empty".into(),
+            },
+            Diagnostic::PopNeverPushed {
+                key: "b".into(),
+                in_key: "origin".into(),
+            },
+            Diagnostic::UnknownModifier {
+                modifier: "shout".into(),
+                in_key: "origin".into(),
+            },
+            Diagnostic::UnreachableKey {
+                key: "bad.key".into(),
+            },
+            Diagnostic::UnreachableKey {
+                key: "empty".into(),
+            },
+        ];
+        assert_eq!(diagnostics, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_missing_default_rule() -> Result<()> {
+        let g = grammar! { "start" => "#missing#" }?;
+        let diagnostics: Vec<_> = g.validate().iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "key 'start' references 'missing', which is never defined",
+                "default rule 'origin' is not defined",
+                "key 'start' is unreachable from the default rule",
+            ]
+        );
+        assert!(g.with_default_rule("start").validate().len() == 1);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_deduplicates_large_grammar() -> Result<()> {
+        // Every key references an undefined key twice and is unreachable
+        let map: Vec<(String, Vec<String>)> = (0..20_000)
+            .map(|i| (format!("k{}", i), vec![format!("#u{}# #u{}#", i, i)]))
+            .collect();
+        let g = Grammar::from_map(map)?.with_default_rule("k0");
+        let diagnostics = g.validate();
+        assert_eq!(diagnostics.len(), 20_000 + 19_999);
+        assert_eq!(
+            diagnostics[0],
+            Diagnostic::UndefinedKey {
+                key: "u0".into(),
+                in_key: "k0".into(),
+            }
+        );
+        Ok(())
+    }
+}